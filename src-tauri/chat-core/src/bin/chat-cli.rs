@@ -0,0 +1,305 @@
+// Terminal client for the chat model, without the Tauri window:
+//
+//   chat-cli [--model DIR] [--draft DIR] [OPTIONS] [chat]                 interactive chat
+//   chat-cli [--model DIR] [--draft DIR] [OPTIONS] generate [--raw] TEXT  answer one question and exit
+//   chat-cli [--model DIR] [--window N] [--stride N] perplexity FILE
+//   chat-cli [--model DIR] score CONTEXT COMPLETION...      log-likelihood of each completion
+//   chat-cli [--model DIR] [--top-logprobs N] logprobs TEXT  logprob of every token of TEXT
+//   chat-cli [--model DIR] [--max-len N] bench TEXT          tokens/sec of prefill and decode
+//
+// OPTIONS are the GenerateOptions fields the chat window takes, written as flags
+// (`--max-len 200 --temperature 0.7 --beam-width 4 --json-schema '{"type":"object"}'`).
+// Both commands use the chat window's preamble and turn template; `--raw` feeds TEXT
+// to the model as is and prints its continuation. `--draft` names a smaller model with the
+// same tokenizer that proposes `--draft-tokens` tokens at a time for speculative decoding. `perplexity`, `score` and `logprobs` measure
+// the model on plain text, without templates, to compare model builds; `bench` times them.
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process;
+
+use chat_core::eval;
+use chat_core::generate::{self, GenerateOptions, Generation};
+use chat_core::grammar::TokenTrie;
+use chat_core::kvcache::KVCache;
+use chat_core::model::{Model, TokenLogprob};
+use chat_core::tensor::Tensor;
+use chat_core::{template, vocab};
+use serde_json::{Map, Value};
+use tokenizers::Tokenizer;
+
+struct Cli {
+    model_dir: PathBuf,
+    draft_dir: Option<PathBuf>,
+    options: GenerateOptions,
+    command: String,
+    text: Vec<String>,
+    raw: bool,
+    window: usize,
+    stride: usize,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: chat-cli [--model DIR] [--draft DIR] [OPTIONS] [chat]");
+    eprintln!("       chat-cli [--model DIR] [--draft DIR] [OPTIONS] generate [--raw] TEXT");
+    eprintln!("       chat-cli [--model DIR] [--window N] [--stride N] perplexity FILE");
+    eprintln!("       chat-cli [--model DIR] score CONTEXT COMPLETION...");
+    eprintln!("       chat-cli [--model DIR] [--top-logprobs N] logprobs TEXT");
+    eprintln!("       chat-cli [--model DIR] [--max-len N] bench TEXT");
+    let defaults = serde_json::to_value(GenerateOptions::default()).unwrap();
+    let flags: Vec<String> = defaults.as_object().unwrap().keys().map(|key| format!("--{}", key.replace('_', "-"))).collect();
+    eprintln!("OPTIONS: {}", flags.join(" "));
+    process::exit(2);
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Cli, String> {
+    let mut model_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..").join("models").join("chat");
+    let mut draft_dir = None;
+    let mut raw = false;
+    let (mut window, mut stride) = (512, 256);
+    let mut fields = Map::new();
+    let mut positional = Vec::new();
+    let defaults = serde_json::to_value(GenerateOptions::default()).unwrap();
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            positional.push(arg);
+            continue;
+        };
+        match flag {
+            "help" => usage(),
+            "raw" => raw = true,
+            "model" => model_dir = args.next().ok_or("--model needs a directory")?.into(),
+            "draft" => draft_dir = Some(args.next().ok_or("--draft needs a directory")?.into()),
+            "window" | "stride" => {
+                let value = args.next().and_then(|value| value.parse().ok());
+                let value = value.ok_or(format!("--{flag} needs a number of tokens"))?;
+                match flag {
+                    "window" => window = value,
+                    _ => stride = value,
+                }
+            }
+            _ => {
+                let key = flag.replace('-', "_");
+                if defaults.get(&key).is_none() {
+                    return Err(format!("unknown option --{flag}"));
+                }
+                let value = args.next().ok_or(format!("--{flag} needs a value"))?;
+                // a grammar is always text, everything else is parsed as JSON when it can be
+                let value = match key.as_str() {
+                    "grammar" => Value::String(value),
+                    _ => serde_json::from_str(&value).unwrap_or(Value::String(value)),
+                };
+                fields.insert(key, value);
+            }
+        }
+    }
+    let options = serde_json::from_value(Value::Object(fields)).map_err(|e| e.to_string())?;
+    let mut positional = positional.into_iter();
+    let command = positional.next().unwrap_or("chat".into());
+    Ok(Cli { model_dir, draft_dir, options, command, text: positional.collect(), raw, window, stride })
+}
+
+// The caches of one conversation: the model's and, with --draft, the draft model's, which hold
+// the same tokens
+#[derive(Clone)]
+struct Caches {
+    model: KVCache<f32>,
+    draft: Option<KVCache<f32>>,
+}
+
+struct Session {
+    model: Model,
+    draft: Option<Model>,
+    tokenizer: Tokenizer,
+    trie: Option<TokenTrie>,
+    options: GenerateOptions,
+}
+
+impl Session {
+    fn encode(&self, text: &str) -> Vec<u32> {
+        self.tokenizer.encode(text, true).unwrap().get_ids().to_vec()
+    }
+
+    fn decode(&self, ids: &[u32]) -> String {
+        self.tokenizer.decode(ids, true).unwrap()
+    }
+
+    fn new_caches(&self) -> Caches {
+        Caches { model: self.model.new_cache(), draft: self.draft.as_ref().map(Model::new_cache) }
+    }
+
+    // Caches holding the preamble every new chat in the app starts from
+    fn start_chat(&self) -> Caches {
+        let mut caches = self.new_caches();
+        let input_ids = self.encode(template::PREAMBLE);
+        let answer = self.model.chat_generate(&input_ids, &mut caches.model, 500, 0.9, 4, 1.);
+        if let (Some(draft), Some(cache)) = (&self.draft, &mut caches.draft) {
+            let fed: Vec<u32> = input_ids.iter().chain(&answer[..answer.len() - 1]).copied().collect();
+            draft.forward(&Tensor::new(fed.clone(), &[fed.len()]), cache);
+        }
+        caches
+    }
+
+    fn answer(&mut self, input: &str, caches: &mut Caches) -> Result<Generation, String> {
+        let input_ids = self.encode(input);
+        let (model, tokenizer, trie) = (&self.model, &self.tokenizer, &mut self.trie);
+        let draft = self.draft.as_ref().zip(caches.draft.as_mut());
+        generate::generate(model, &input_ids, &mut caches.model, draft, &self.options, || {
+            trie.get_or_insert_with(|| TokenTrie::new(vocab::token_bytes(tokenizer)))
+        }, |_| true)
+    }
+
+    fn print(&self, generation: &Generation) {
+        let candidates = &generation.candidates;
+        println!("{}", self.decode(&candidates[0].0));
+        if candidates.len() > 1 {
+            for (i, (ids, logprob)) in candidates.iter().enumerate() {
+                println!("[{i}] ({logprob:.3}) {}", self.decode(ids));
+            }
+        }
+        if let Some(steps) = &generation.logprobs {
+            self.print_logprobs(steps);
+        }
+    }
+
+    fn print_logprobs(&self, steps: &[TokenLogprob]) {
+        for step in steps {
+            let top: Vec<String> = step
+                .top
+                .iter()
+                .map(|&(id, logprob)| format!("{:?} {logprob:.3}", self.tokenizer.decode(&[id], false).unwrap()))
+                .collect();
+            let token = self.tokenizer.decode(&[step.token], false).unwrap();
+            println!("{token:?}\t{:.3}\t{}", step.logprob, top.join(", "));
+        }
+    }
+}
+
+fn chat(mut session: Session) {
+    println!("/reset starts a new conversation, /exit quits");
+    let start = session.start_chat();
+    let mut caches = start.clone();
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        match line.trim() {
+            "" => continue,
+            "/exit" | "/quit" => break,
+            "/reset" => caches = start.clone(),
+            question => {
+                // a failed answer leaves the conversation as it was
+                let before = caches.clone();
+                match session.answer(&template::user_turn(question), &mut caches) {
+                    Ok(generation) => session.print(&generation),
+                    Err(e) => {
+                        eprintln!("grammar error: {e}");
+                        caches = before;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn perplexity(session: &Session, path: &str, window: usize, stride: usize) {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        process::exit(1);
+    });
+    let token_ids = session.encode(&text);
+    let result = eval::perplexity(&session.model, &token_ids, window, stride);
+    println!("tokens: {}  nll: {:.4}  perplexity: {:.4}", result.n_tokens, result.nll, result.value());
+}
+
+fn score(session: &Session, context: &str, completions: &[String]) {
+    let context_ids = session.encode(context);
+    if context_ids.is_empty() {
+        eprintln!("CONTEXT must encode to at least one token");
+        usage();
+    }
+    let completion_ids: Vec<Vec<u32>> = completions
+        .iter()
+        .map(|completion| session.tokenizer.encode(completion.as_str(), false).unwrap().get_ids().to_vec())
+        .collect();
+    let scores = eval::score_completions(&session.model, &context_ids, &completion_ids).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    });
+    let best = (0..scores.len()).max_by(|&a, &b| scores[a].mean_logprob().total_cmp(&scores[b].mean_logprob()));
+    for (i, (completion, score)) in completions.iter().zip(&scores).enumerate() {
+        let mark = if Some(i) == best { "*" } else { " " };
+        println!("{mark} [{i}] logprob {:.4}  per token {:.4}  {completion:?}", score.logprob, score.mean_logprob());
+    }
+}
+
+// Prefills TEXT and decodes max_len tokens greedily, three times, as the first run also
+// warms up the caches and allocations
+fn bench(session: &Session, text: &str) {
+    let token_ids = session.encode(text);
+    for _ in 0..3 {
+        let result = eval::throughput(&session.model, &token_ids, session.options.max_len);
+        println!(
+            "prefill: {} tokens {:.1} tok/s  decode: {} tokens {:.1} tok/s",
+            result.prompt_tokens,
+            result.prefill_rate(),
+            result.decoded_tokens,
+            result.decode_rate()
+        );
+    }
+}
+
+fn main() {
+    let cli = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        usage()
+    });
+    let min_args = match cli.command.as_str() {
+        "chat" => 0,
+        "generate" | "perplexity" | "logprobs" | "bench" => 1,
+        "score" => 2,
+        command => {
+            eprintln!("unknown command {command}");
+            usage()
+        }
+    };
+    if cli.text.len() < min_args {
+        usage();
+    }
+    let mut session = Session {
+        model: Model::from_safetensors(&cli.model_dir),
+        draft: cli.draft_dir.as_ref().map(Model::from_safetensors),
+        tokenizer: Tokenizer::from_file(cli.model_dir.join("tokenizer.json")).unwrap(),
+        trie: None,
+        options: cli.options,
+    };
+    match cli.command.as_str() {
+        "chat" => return chat(session),
+        "perplexity" => return perplexity(&session, &cli.text[0], cli.window, cli.stride),
+        "score" => return score(&session, &cli.text[0], &cli.text[1..]),
+        "bench" => return bench(&session, &cli.text.join(" ")),
+        "logprobs" => {
+            let token_ids = session.encode(&cli.text.join(" "));
+            let steps = eval::prompt_logprobs(&session.model, &token_ids, session.options.top_logprobs);
+            return session.print_logprobs(&steps);
+        }
+        _ => {}
+    }
+
+    let text = cli.text.join(" ");
+    let (mut caches, input) = match cli.raw {
+        true => (session.new_caches(), text),
+        false => (session.start_chat(), template::user_turn(&text)),
+    };
+    match session.answer(&input, &mut caches) {
+        Ok(generation) => session.print(&generation),
+        Err(e) => {
+            eprintln!("grammar error: {e}");
+            process::exit(1);
+        }
+    }
+}
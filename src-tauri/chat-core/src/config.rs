@@ -0,0 +1,217 @@
+use serde;
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct LlamaConfigJson {
+    pub bos_token_id: u32,
+    pub eos_token_id: u32,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub max_position_embeddings: usize,
+    pub num_attention_heads: usize,
+    pub num_hidden_layers: usize,
+    pub num_key_value_heads: usize,
+    pub vocab_size: usize,
+    #[serde(default = "default_rms_norm_eps")]
+    pub rms_norm_eps: f32,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
+    pub torch_dtype: String,
+    #[serde(default = "default_tie_word_embeddings")]
+    pub tie_word_embeddings: bool,
+    #[serde(default)]
+    pub model_type: String,
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    // Mistral-style sliding-window attention over the last sliding_window positions; Qwen2
+    // configs carry a window but only use it with use_sliding_window
+    #[serde(default)]
+    pub sliding_window: Option<usize>,
+    #[serde(default = "default_use_sliding_window")]
+    pub use_sliding_window: bool,
+    // Llama configs can turn on q/k/v/o biases explicitly
+    #[serde(default)]
+    pub attention_bias: bool,
+    // mixture of experts: Mixtral names the expert count num_local_experts, Qwen-MoE num_experts
+    #[serde(default)]
+    pub num_local_experts: Option<usize>,
+    #[serde(default)]
+    pub num_experts: Option<usize>,
+    #[serde(default = "default_num_experts_per_tok")]
+    pub num_experts_per_tok: usize,
+    // whether the top-k routing weights are rescaled to sum to 1 (Mixtral always does)
+    #[serde(default)]
+    pub norm_topk_prob: Option<bool>,
+    // Qwen-MoE: every decoder_sparse_step-th layer is sparse, except the mlp_only_layers
+    #[serde(default = "default_decoder_sparse_step")]
+    pub decoder_sparse_step: usize,
+    #[serde(default)]
+    pub mlp_only_layers: Vec<usize>,
+}
+
+// How rotary frequencies are stretched past the context length the model was trained on
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RopeType {
+    Default,
+    Linear,
+    Dynamic,
+    Yarn,
+    Llama3,
+}
+
+// config.json's rope_scaling; older configs name the variant `type`, newer ones `rope_type`,
+// some write both
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RopeScaling {
+    #[serde(default)]
+    pub rope_type: Option<RopeType>,
+    #[serde(default, rename = "type")]
+    pub legacy_type: Option<RopeType>,
+    #[serde(default = "default_rope_factor")]
+    pub factor: f32,
+    pub original_max_position_embeddings: Option<usize>,
+    // llama3
+    pub low_freq_factor: Option<f32>,
+    pub high_freq_factor: Option<f32>,
+    // yarn
+    pub beta_fast: Option<f32>,
+    pub beta_slow: Option<f32>,
+    pub attention_factor: Option<f32>,
+    pub mscale: Option<f32>,
+    pub mscale_all_dim: Option<f32>,
+}
+
+impl RopeScaling {
+    pub fn kind(&self) -> RopeType {
+        self.rope_type.or(self.legacy_type).unwrap_or(RopeType::Default)
+    }
+}
+
+// Model families the engine knows how to load
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Architecture {
+    Llama,
+    Mistral,  // Llama layout, usually with sliding-window attention
+    Qwen2,    // Llama layout plus biases on the q/k/v projections
+    Mixtral,  // Mistral with a mixture-of-experts MLP
+    Qwen2Moe, // Qwen2 with a mixture-of-experts MLP plus a shared expert
+}
+
+impl LlamaConfigJson {
+    // Detected from model_type, else from the class names in architectures; defaults to Llama
+    pub fn architecture(&self) -> Architecture {
+        let is = |family: &str| {
+            let matches = |name: &str| name.to_ascii_lowercase().replace('_', "").starts_with(family);
+            matches(&self.model_type) || self.architectures.iter().any(|a| matches(a))
+        };
+        if is("qwen2moe") {
+            Architecture::Qwen2Moe
+        } else if is("qwen2") {
+            Architecture::Qwen2
+        } else if is("mixtral") {
+            Architecture::Mixtral
+        } else if is("mistral") {
+            Architecture::Mistral
+        } else {
+            Architecture::Llama
+        }
+    }
+
+    // Attention window, None when every position attends to the whole sequence
+    pub fn sliding_window(&self) -> Option<usize> {
+        self.sliding_window.filter(|&window| self.use_sliding_window && window < self.max_position_embeddings)
+    }
+
+    // Whether the q/k/v projections carry bias vectors
+    pub fn qkv_bias(&self) -> bool {
+        self.attention_bias || matches!(self.architecture(), Architecture::Qwen2 | Architecture::Qwen2Moe)
+    }
+
+    // Number of experts in a sparse MLP layer, 0 for dense models
+    pub fn n_experts(&self) -> usize {
+        self.num_local_experts.or(self.num_experts).unwrap_or(0)
+    }
+
+    // Whether decoder layer `layer` uses the mixture-of-experts MLP
+    pub fn is_sparse_layer(&self, layer: usize) -> bool {
+        self.n_experts() > 0
+            && !self.mlp_only_layers.contains(&layer)
+            && (layer + 1).is_multiple_of(self.decoder_sparse_step.max(1))
+    }
+
+    pub fn norm_topk_prob(&self) -> bool {
+        self.norm_topk_prob.unwrap_or(self.architecture() != Architecture::Qwen2Moe)
+    }
+}
+
+#[inline(always)]
+const fn default_rms_norm_eps() -> f32 {
+    1e-5
+}
+
+#[inline(always)]
+const fn default_rope_theta() -> f32 {
+    1e4
+}
+
+#[inline(always)]
+const fn default_num_experts_per_tok() -> usize {
+    2
+}
+
+#[inline(always)]
+const fn default_decoder_sparse_step() -> usize {
+    1
+}
+
+#[inline(always)]
+const fn default_use_sliding_window() -> bool {
+    true
+}
+
+#[inline(always)]
+const fn default_rope_factor() -> f32 {
+    1.
+}
+
+#[inline(always)]
+const fn default_tie_word_embeddings() -> bool {
+    false
+}
+
+#[test]
+fn test_architecture() {
+    let config = |extra: &str| -> LlamaConfigJson {
+        let json = format!(
+            r#"{{"bos_token_id": 1, "eos_token_id": 2, "hidden_size": 8, "intermediate_size": 16,
+            "max_position_embeddings": 32, "num_attention_heads": 2, "num_hidden_layers": 1,
+            "num_key_value_heads": 2, "vocab_size": 10, "torch_dtype": "float32"{extra}}}"#
+        );
+        serde_json::from_str(&json).unwrap()
+    };
+    assert_eq!(config("").architecture(), Architecture::Llama);
+    assert!(!config("").qkv_bias());
+    assert!(config(r#", "attention_bias": true"#).qkv_bias());
+    assert_eq!(config(r#", "model_type": "qwen2""#).architecture(), Architecture::Qwen2);
+    let qwen = config(r#", "model_type": "", "architectures": ["Qwen2ForCausalLM"]"#);
+    assert_eq!(qwen.architecture(), Architecture::Qwen2);
+    assert!(qwen.qkv_bias());
+    let mistral = config(r#", "model_type": "mistral", "sliding_window": 16"#);
+    assert_eq!(mistral.architecture(), Architecture::Mistral);
+    assert_eq!(mistral.sliding_window(), Some(16));
+    assert_eq!(config(r#", "sliding_window": 16, "use_sliding_window": false"#).sliding_window(), None);
+    assert_eq!(config(r#", "sliding_window": 4096"#).sliding_window(), None); // longer than the context
+
+    let mixtral = config(r#", "architectures": ["MixtralForCausalLM"], "num_local_experts": 8"#);
+    assert_eq!(mixtral.architecture(), Architecture::Mixtral);
+    assert!(mixtral.is_sparse_layer(0) && mixtral.norm_topk_prob() && !mixtral.qkv_bias());
+    let qwen_moe = config(
+        r#", "model_type": "qwen2_moe", "num_experts": 4, "decoder_sparse_step": 2, "mlp_only_layers": [3]"#,
+    );
+    assert_eq!(qwen_moe.architecture(), Architecture::Qwen2Moe);
+    assert!(qwen_moe.qkv_bias() && !qwen_moe.norm_topk_prob());
+    let sparse: Vec<bool> = (0..6).map(|layer| qwen_moe.is_sparse_layer(layer)).collect();
+    assert_eq!(sparse, [false, true, false, false, false, true]);
+    assert!(!config("").is_sparse_layer(0));
+}
@@ -0,0 +1,227 @@
+// Model quality measurements: perplexity of a text, log-likelihood of candidate completions
+// and per-token logprobs of a prompt, plus the inference speed
+use std::time::{Duration, Instant};
+
+use crate::kvcache::KVCache;
+use crate::model::{Model, TokenLogprob};
+use crate::operators as OP;
+use crate::tensor::Tensor;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Perplexity {
+    pub nll: f64,        // summed negative log-likelihood of the scored tokens
+    pub n_tokens: usize, // number of scored tokens (every token but the first)
+}
+
+impl Perplexity {
+    pub fn value(&self) -> f64 {
+        (self.nll / self.n_tokens.max(1) as f64).exp()
+    }
+}
+
+// Log-likelihood of one completion given the shared context
+#[derive(Clone, Copy, Debug)]
+pub struct CompletionScore {
+    pub logprob: f32,
+    pub n_tokens: usize,
+}
+
+impl CompletionScore {
+    // Per-token logprob, which does not favour short completions
+    pub fn mean_logprob(&self) -> f32 {
+        self.logprob / self.n_tokens.max(1) as f32
+    }
+}
+
+// Time spent evaluating a prompt and then decoding one token at a time
+#[derive(Clone, Copy, Debug)]
+pub struct Throughput {
+    pub prompt_tokens: usize,
+    pub prefill: Duration,
+    pub decoded_tokens: usize,
+    pub decode: Duration,
+}
+
+impl Throughput {
+    // 0 when nothing was measured
+    pub fn prefill_rate(&self) -> f64 {
+        rate(self.prompt_tokens, self.prefill)
+    }
+
+    pub fn decode_rate(&self) -> f64 {
+        rate(self.decoded_tokens, self.decode)
+    }
+}
+
+fn rate(n_tokens: usize, elapsed: Duration) -> f64 {
+    match n_tokens {
+        0 => 0.,
+        n => n as f64 / elapsed.as_secs_f64(),
+    }
+}
+
+// Sliding windows (begin, end, first_target) over n_tokens tokens: each window of at most
+// `window` tokens starts `stride` after the previous one and only scores the targets
+// first_target..end the previous windows have not, so every token but the first is scored
+// exactly once with up to window - stride tokens of context.
+fn windows(n_tokens: usize, window: usize, stride: usize) -> Vec<(usize, usize, usize)> {
+    let mut windows = Vec::new();
+    let mut scored = 1;
+    let mut begin = 0;
+    while scored < n_tokens {
+        let end = (begin + window).min(n_tokens);
+        windows.push((begin, end, scored));
+        scored = end;
+        begin += stride;
+    }
+    windows
+}
+
+// Perplexity of token_ids, evaluated in windows of `window` tokens (at most max_seq_len)
+// moved forward by `stride` (at most window).
+pub fn perplexity(model: &Model, token_ids: &[u32], window: usize, stride: usize) -> Perplexity {
+    let window = window.clamp(2, model.max_seq_len());
+    let stride = stride.clamp(1, window - 1);
+    let mut cache = model.new_cache();
+    let mut nll = 0.;
+    for (begin, end, first_target) in windows(token_ids.len(), window, stride) {
+        cache.reset_len(0);
+        // row i of the logits predicts token begin + i + 1
+        let input = Tensor::<u32>::new(token_ids[begin..end - 1].to_vec(), &[end - 1 - begin]);
+        let logits = model.forward_all(&input, &mut cache);
+        let vocab = logits.shape()[1];
+        for (i, &target) in token_ids[first_target..end].iter().enumerate() {
+            let row = logits.slice((first_target - 1 - begin + i) * vocab, &[1, vocab]);
+            nll -= OP::log_softmax(&row)[target as usize] as f64;
+        }
+    }
+    Perplexity { nll, n_tokens: token_ids.len().saturating_sub(1) }
+}
+
+// Log-likelihood of each completion following context (multiple-choice scoring). The context
+// is evaluated once and every completion continues from the same cache. The context must not
+// be empty and must fit in max_seq_len with each completion but its last token, which is only
+// scored; scores of truncated completions would not be comparable, so that is an error.
+pub fn score_completions(model: &Model, context: &[u32], completions: &[Vec<u32>]) -> Result<Vec<CompletionScore>, String> {
+    if context.is_empty() {
+        return Err("scoring needs at least one context token".into());
+    }
+    let longest = completions.iter().map(Vec::len).max().unwrap_or(0);
+    let positions = context.len() + longest.saturating_sub(1);
+    if positions > model.max_seq_len() {
+        return Err(format!(
+            "context and completion need {positions} positions ({} + {longest} tokens), the model has {}",
+            context.len(),
+            model.max_seq_len()
+        ));
+    }
+    let prefill = |cache: &mut KVCache<f32>| {
+        cache.reset_len(0);
+        model.forward(&Tensor::<u32>::new(context.to_vec(), &[context.len()]), cache)
+    };
+    let mut cache = model.new_cache();
+    let first = OP::log_softmax(&prefill(&mut cache));
+    Ok(completions
+        .iter()
+        .map(|completion| {
+            // a ring cache may have overwritten the context's last window with a long completion
+            if cache.retains(context.len()) {
+                cache.reset_len(context.len());
+            } else {
+                prefill(&mut cache);
+            }
+            let Some((&head, _)) = completion.split_first() else {
+                return CompletionScore { logprob: 0., n_tokens: 0 };
+            };
+            let mut logprob = first[head as usize];
+            let n = completion.len();
+            if n > 1 {
+                let input = Tensor::<u32>::new(completion[..n - 1].to_vec(), &[n - 1]);
+                let logits = model.forward_all(&input, &mut cache);
+                let vocab = logits.shape()[1];
+                for (i, &token) in completion[1..].iter().enumerate() {
+                    let row = logits.slice(i * vocab, &[1, vocab]);
+                    logprob += OP::log_softmax(&row)[token as usize];
+                }
+            }
+            CompletionScore { logprob, n_tokens: n }
+        })
+        .collect())
+}
+
+// Logprob of every prompt token but the first given the tokens before it, with the top_n most
+// likely tokens at each position. Prompts longer than max_seq_len are truncated.
+pub fn prompt_logprobs(model: &Model, token_ids: &[u32], top_n: usize) -> Vec<TokenLogprob> {
+    let token_ids = &token_ids[..token_ids.len().min(model.max_seq_len())];
+    if token_ids.len() < 2 {
+        return Vec::new();
+    }
+    let n = token_ids.len() - 1;
+    let mut cache = model.new_cache();
+    let logits = model.forward_all(&Tensor::<u32>::new(token_ids[..n].to_vec(), &[n]), &mut cache);
+    let vocab = logits.shape()[1];
+    (0..n)
+        .map(|i| {
+            let logprobs = OP::log_softmax(&logits.slice(i * vocab, &[1, vocab]));
+            let token = token_ids[i + 1];
+            TokenLogprob { token, logprob: logprobs[token as usize], top: OP::top_n(&logprobs, top_n) }
+        })
+        .collect()
+}
+
+// Tokens per second of prefilling token_ids and then greedily decoding n_tokens more, without
+// stopping at eos, so runs of different builds do the same work. An empty prompt measures nothing.
+pub fn throughput(model: &Model, token_ids: &[u32], n_tokens: usize) -> Throughput {
+    let token_ids = &token_ids[..token_ids.len().min(model.max_seq_len() - 1)];
+    if token_ids.is_empty() {
+        return Throughput { prompt_tokens: 0, prefill: Duration::ZERO, decoded_tokens: 0, decode: Duration::ZERO };
+    }
+    let n_tokens = n_tokens.min(model.max_seq_len() - token_ids.len());
+    let mut cache = model.new_cache();
+    let start = Instant::now();
+    let mut logits = model.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), &mut cache);
+    let prefill = start.elapsed();
+    let start = Instant::now();
+    for _ in 0..n_tokens {
+        let next = OP::random_sample(&logits, 1., 1, 0.);
+        logits = model.forward(&Tensor::new(vec![next], &[1]), &mut cache);
+    }
+    Throughput { prompt_tokens: token_ids.len(), prefill, decoded_tokens: n_tokens, decode: start.elapsed() }
+}
+
+#[test]
+fn test_windows() {
+    // every target scored exactly once, within its window, with the window size respected
+    for (n, window, stride) in [(10, 4, 2), (10, 4, 3), (10, 16, 8), (9, 3, 1), (1, 4, 2), (0, 4, 2)] {
+        let mut scored = vec![0; n];
+        for (begin, end, first_target) in windows(n, window, stride) {
+            assert!(end - begin <= window && begin < first_target && first_target <= end);
+            (first_target..end).for_each(|t| scored[t] += 1);
+        }
+        assert!(scored.iter().skip(1).all(|&count| count == 1), "{n} {window} {stride}");
+    }
+    assert_eq!(windows(10, 4, 2), vec![(0, 4, 1), (2, 6, 4), (4, 8, 6), (6, 10, 8)]);
+}
+
+#[test]
+fn test_throughput_empty_prompt() {
+    let model = Model::new(crate::model::Counter::new(5));
+    let result = throughput(&model, &[], 4);
+    assert_eq!((result.prompt_tokens, result.decoded_tokens), (0, 0));
+    assert_eq!((result.prefill_rate(), result.decode_rate()), (0., 0.));
+    // a prompt filling all but one position leaves room for a single decoded token
+    let result = throughput(&model, &[1; 20], 4);
+    assert_eq!((result.prompt_tokens, result.decoded_tokens), (15, 1));
+}
+
+#[test]
+fn test_score_completions_length() {
+    let model = Model::new(crate::model::Counter::new(5));
+    assert!(score_completions(&model, &[], &[vec![1]]).is_err());
+    // the last completion token is only scored: 10 + 6 positions fit the 16, 10 + 7 do not
+    let scores = score_completions(&model, &[1; 10], &[vec![1], vec![1; 7]]).unwrap();
+    assert_eq!((scores[0].n_tokens, scores[1].n_tokens), (1, 7));
+    assert!(score_completions(&model, &[1; 10], &[vec![1], vec![1; 8]]).is_err());
+    assert!(score_completions(&model, &[1; 17], &[vec![1]]).is_err());
+    assert_eq!(score_completions(&model, &[1; 16], &[vec![1], vec![]]).unwrap().len(), 2);
+}
@@ -0,0 +1,128 @@
+// Sampling options and the decoding-mode dispatch shared by the chat window, the HTTP server
+// and the terminal client
+use crate::grammar::{Grammar, GrammarConstraint, TokenTrie};
+use crate::kvcache::KVCache;
+use crate::model::{Model, TokenLogprob};
+use crate::tensor::Tensor;
+
+// 生成参数, 前端不传时与原来固定的采样参数一致
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GenerateOptions {
+    #[serde(default = "default_max_len")]
+    pub max_len: usize,
+    #[serde(default = "default_top_p")]
+    pub top_p: f32,
+    #[serde(default = "default_top_k")]
+    pub top_k: u32,
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    // beam_width > 1 时使用 beam search
+    #[serde(default)]
+    pub beam_width: usize,
+    #[serde(default = "default_length_penalty")]
+    pub length_penalty: f32,
+    #[serde(default)]
+    pub early_stopping: bool,
+    // n_samples > 1 时返回多个候选答案
+    #[serde(default)]
+    pub n_samples: usize,
+    // 返回每个 token 的对数概率及 top_logprobs 个备选 token (仅普通采样模式)
+    #[serde(default)]
+    pub logprobs: bool,
+    #[serde(default)]
+    pub top_logprobs: usize,
+    // 约束生成: GBNF 语法或 JSON schema, 二选一
+    #[serde(default)]
+    pub grammar: Option<String>,
+    #[serde(default)]
+    pub json_schema: Option<serde_json::Value>,
+    // 有草稿模型时 (投机解码), 每步由草稿模型提出的 token 数
+    #[serde(default = "default_draft_tokens")]
+    pub draft_tokens: usize,
+}
+
+fn default_max_len() -> usize { 500 }
+fn default_top_p() -> f32 { 0.9 }
+fn default_top_k() -> u32 { 4 }
+fn default_temperature() -> f32 { 1. }
+fn default_length_penalty() -> f32 { 1. }
+fn default_draft_tokens() -> usize { 4 }
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+// Candidate answers (best first) with their cumulative logprobs, plus the per-token
+// logprobs of the first one when `logprobs` was requested
+pub struct Generation {
+    pub candidates: Vec<(Vec<u32>, f32)>,
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+// The grammar `options` constrains the answer to, if any: an invalid grammar or schema is an error
+pub fn grammar(options: &GenerateOptions) -> Result<Option<Grammar>, String> {
+    match (&options.grammar, &options.json_schema) {
+        (Some(src), _) => Grammar::parse(src).map(Some),
+        (None, Some(schema)) => Grammar::from_json_schema(schema).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+// Generates an answer to `token_ids` in whichever mode `options` selects: grammar / JSON schema
+// constrained, beam search, n samples, sampling with logprobs or plain sampling.
+// `trie` is only called when a grammar is given; the errors are an invalid grammar or schema and
+// a constrained answer that does not complete its grammar (see Model::constrained_generate).
+// In plain sampling mode on_token sees every token as it is sampled, to show partial output,
+// and returning false stops the answer there. With a draft model (and its cache, holding the
+// same history as `cache`) plain sampling decodes speculatively and on_token sees the answer
+// at the end; every mode keeps the draft cache in step.
+pub fn generate<'t>(
+    model: &Model,
+    token_ids: &[u32],
+    cache: &mut KVCache<f32>,
+    mut draft: Option<(&Model, &mut KVCache<f32>)>,
+    options: &GenerateOptions,
+    trie: impl FnOnce() -> &'t TokenTrie,
+    mut on_token: impl FnMut(u32) -> bool,
+) -> Result<Generation, String> {
+    let o = options;
+    let start = cache.len();
+    if let Some((_, draft_cache)) = &draft {
+        assert!(draft_cache.len() == start, "draft cache must hold the same history");
+    }
+    let mut logprobs = None;
+    let candidates = if let Some(grammar) = grammar(o)? {
+        let mut constraint = GrammarConstraint::new(&grammar, trie(), model.eos_token_id());
+        vec![(model.constrained_generate(token_ids, cache, o, &mut constraint)?, 0.)]
+    } else if o.beam_width > 1 {
+        model.beam_search(token_ids, cache, o)
+    } else if o.n_samples > 1 {
+        model.sample_n(token_ids, cache, o)
+    } else if o.logprobs {
+        let steps = model.chat_generate_logprobs(token_ids, cache, o);
+        let answer = (steps.iter().map(|step| step.token).collect(), steps.iter().map(|step| step.logprob).sum());
+        logprobs = Some(steps);
+        vec![answer]
+    } else if let Some((draft, draft_cache)) = &mut draft {
+        let tokens = model.speculative_generate(draft, token_ids, cache, draft_cache, o);
+        for &token in &tokens {
+            if !on_token(token) {
+                break;
+            }
+        }
+        vec![(tokens, 0.)]
+    } else {
+        vec![(model.chat_generate_stream(token_ids, cache, o, on_token), 0.)]
+    };
+    // other modes only fed `cache`: feed the draft model the same tokens
+    if let Some((draft, draft_cache)) = draft {
+        let answer = candidates.first().map_or(&[][..], |(tokens, _)| tokens);
+        let fed: Vec<u32> = token_ids.iter().chain(answer).copied().take(cache.len() - start).skip(draft_cache.len() - start).collect();
+        if !fed.is_empty() {
+            draft.forward(&Tensor::new(fed.clone(), &[fed.len()]), draft_cache);
+        }
+    }
+    Ok(Generation { candidates, logprobs })
+}
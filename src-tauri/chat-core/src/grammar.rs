@@ -0,0 +1,868 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::tensor::Tensor;
+
+// A GBNF-style grammar (the format llama.cpp uses):
+//
+//   root   ::= "{" ws pair ("," ws pair)* "}"
+//   pair   ::= [a-z]+ ws ":" ws ("true" | "false")
+//   ws     ::= [ \t\n]{0,4}
+//
+// Rules are `name ::= alternatives`, alternatives are separated by `|`, and elements are
+// "literals", [character classes] / [^negated ones], `.`, rule names and ( groups ),
+// each optionally followed by `*`, `+`, `?` or `{m,n}`. A rule ends at the end of its line unless
+// the next line continues it with `|`; `#` starts a comment. Generation starts at `root`.
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Element>>>, // rule -> alternatives -> sequence
+    start: Vec<Stack>,
+    closures: HashMap<Pos, Vec<Stack>>, // see closure()
+}
+
+#[derive(Clone, Debug)]
+enum Element {
+    Chars { ranges: Vec<(char, char)>, negated: bool },
+    Rule(usize),
+}
+
+// Position of the next element to match: (rule, alternative, element).
+// A stack is the chain of positions to return to; its top is always a Chars element,
+// and an empty stack means the input so far is a complete sentence.
+type Pos = (usize, usize, usize);
+type Stack = Vec<Pos>;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct State {
+    stacks: Vec<Stack>,
+    partial: Vec<u8>, // bytes of an unfinished UTF-8 char
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            src: src.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rules: Vec::new(),
+        };
+        parser.parse_grammar()?;
+        let mut rules = Vec::with_capacity(parser.rules.len());
+        let mut names: Vec<(&String, &usize)> = parser.names.iter().collect();
+        names.sort_by_key(|(_, id)| **id);
+        for (rule, alts) in parser.rules.into_iter().enumerate() {
+            match alts {
+                Some(alts) => rules.push(alts),
+                None => {
+                    let name = names.iter().find(|(_, id)| **id == rule).unwrap().0;
+                    return Err(format!("undefined rule `{name}`"));
+                }
+            }
+        }
+        let root = *parser.names.get("root").ok_or("missing `root` rule")?;
+        check_left_recursion(&rules)?;
+
+        let mut closures = HashMap::new();
+        for (r, alts) in rules.iter().enumerate() {
+            for (a, seq) in alts.iter().enumerate() {
+                for i in 0..=seq.len() {
+                    closure(&rules, (r, a, i), &mut closures);
+                }
+            }
+        }
+        let mut grammar = Grammar { rules, start: Vec::new(), closures };
+        let mut start = Vec::new();
+        for alt in 0..grammar.rules[root].len() {
+            grammar.expand(vec![(root, alt, 0)], &mut start);
+        }
+        start.sort();
+        start.dedup();
+        grammar.start = start;
+        Ok(grammar)
+    }
+
+    // Compiles a JSON schema to a grammar accepting the JSON documents it describes
+    pub fn from_json_schema(schema: &Value) -> Result<Self, String> {
+        Self::parse(&json_schema_to_gbnf(schema)?)
+    }
+
+    // Whether the grammar accepts `text` as a complete sentence
+    #[allow(unused)]
+    pub fn accepts(&self, text: &str) -> bool {
+        let mut stacks = self.start.clone();
+        for c in text.chars() {
+            stacks = self.advance(&stacks, c);
+            if stacks.is_empty() {
+                return false;
+            }
+        }
+        stacks.iter().any(|s| s.is_empty())
+    }
+
+    // Normalizes a stack so its top is a Chars element, pushing one stack per way of
+    // entering the referenced rules. Finished sequences return to their parent.
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        let Some(pos) = stack.pop() else {
+            return out.push(stack);
+        };
+        for top in &self.closures[&pos] {
+            if top.is_empty() {
+                self.expand(stack.clone(), out);
+            } else {
+                let mut s = stack.clone();
+                s.extend_from_slice(top);
+                out.push(s);
+            }
+        }
+    }
+
+    fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            let (r, a, i) = match stack.last() {
+                Some(&pos) => pos,
+                None => continue,
+            };
+            if let Element::Chars { ranges, negated } = &self.rules[r][a][i] {
+                if ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated {
+                    let mut s = stack.clone();
+                    s.pop();
+                    if i + 1 < self.rules[r][a].len() {
+                        s.push((r, a, i + 1));
+                    }
+                    self.expand(s, &mut out);
+                }
+            }
+        }
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    // Whether the char expected on top of `stack` could be any of lo..=hi
+    fn may_match(&self, stack: &Stack, lo: char, hi: char) -> bool {
+        let (r, a, i) = match stack.last() {
+            Some(&pos) => pos,
+            None => return false,
+        };
+        match &self.rules[r][a][i] {
+            Element::Chars { ranges, negated: false } => ranges.iter().any(|&(l, h)| l <= hi && lo <= h),
+            Element::Chars { ranges, negated: true } => !ranges.iter().any(|&(l, h)| l <= lo && hi <= h),
+            Element::Rule(_) => true,
+        }
+    }
+
+    fn feed_byte(&self, state: &State, byte: u8) -> Option<State> {
+        let mut partial = state.partial.clone();
+        partial.push(byte);
+        let need = match partial[0] {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return None,
+        };
+        if partial.len() > 1 && byte & 0xc0 != 0x80 {
+            return None;
+        }
+        if partial.len() < need {
+            // keep the prefix if some stack expects a char in the range it can still become
+            let bound = |fill: u8| {
+                let mut bytes = partial.clone();
+                bytes.resize(need, fill);
+                std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next())
+            };
+            let possible = match (bound(0x80), bound(0xbf)) {
+                (Some(lo), Some(hi)) => state.stacks.iter().any(|s| self.may_match(s, lo, hi)),
+                _ => state.stacks.iter().any(|s| !s.is_empty()),
+            };
+            return match possible {
+                true => Some(State { stacks: state.stacks.clone(), partial }),
+                false => None,
+            };
+        }
+        let c = std::str::from_utf8(&partial).ok()?.chars().next()?;
+        let stacks = self.advance(&state.stacks, c);
+        if stacks.is_empty() {
+            return None;
+        }
+        Some(State { stacks, partial: Vec::new() })
+    }
+}
+
+// Token ids arranged by their bytes, so that checking the whole vocab against a grammar
+// state walks each shared prefix only once
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+    token_bytes: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u32>,
+}
+
+impl TokenTrie {
+    // `token_bytes[id]` is the text of token `id`; empty entries can never be generated
+    pub fn new(token_bytes: Vec<Vec<u8>>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in token_bytes.iter().enumerate() {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for &b in bytes {
+                node = match nodes[node].children.iter().find(|(c, _)| *c == b) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((b, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id as u32);
+        }
+        TokenTrie { nodes, token_bytes }
+    }
+}
+
+// Masks logits so that sampling can only continue the grammar. Masks are cached per
+// grammar state, which repeat a lot in practice (e.g. every char inside a JSON string).
+pub struct GrammarConstraint<'a> {
+    grammar: &'a Grammar,
+    trie: &'a TokenTrie,
+    eos_token_id: u32,
+    state: State,
+    masks: HashMap<State, Vec<bool>>,
+}
+
+impl<'a> GrammarConstraint<'a> {
+    pub fn new(grammar: &'a Grammar, trie: &'a TokenTrie, eos_token_id: u32) -> Self {
+        GrammarConstraint {
+            grammar,
+            trie,
+            eos_token_id,
+            state: State { stacks: grammar.start.clone(), partial: Vec::new() },
+            masks: HashMap::new(),
+        }
+    }
+
+    // Whether the text accepted so far is a complete sentence, i.e. eos may follow
+    pub fn is_complete(&self) -> bool {
+        self.state.partial.is_empty() && self.state.stacks.iter().any(|s| s.is_empty())
+    }
+
+    // Sets the logits of every token that would break the grammar to -inf
+    pub fn apply(&mut self, logits: &mut Tensor<f32>) {
+        if !self.masks.contains_key(&self.state) {
+            let mut allowed = vec![false; self.trie.token_bytes.len()];
+            self.collect(0, &self.state, &mut allowed);
+            self.masks.insert(self.state.clone(), allowed);
+        }
+        let allowed = &self.masks[&self.state];
+        // eos once the sentence is complete, or as the only way out of a dead end
+        let eos = self.is_complete() || !allowed.contains(&true);
+        let data = logits.data_mut();
+        for (tok, logit) in data.iter_mut().enumerate() {
+            let keep = allowed.get(tok) == Some(&true) || (eos && tok as u32 == self.eos_token_id);
+            if !keep {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    // Advances the grammar state past a sampled token; a token the grammar does not allow
+    // is an error and leaves the state unchanged
+    pub fn accept(&mut self, token: u32) -> Result<(), String> {
+        if token == self.eos_token_id {
+            return Ok(());
+        }
+        let mut state = self.state.clone();
+        for &b in &self.trie.token_bytes[token as usize] {
+            state = self.grammar.feed_byte(&state, b).ok_or_else(|| format!("token {token} is not allowed by the grammar"))?;
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    fn collect(&self, node: usize, state: &State, allowed: &mut Vec<bool>) {
+        for &(b, child) in &self.trie.nodes[node].children {
+            if let Some(next) = self.grammar.feed_byte(state, b) {
+                for &tok in &self.trie.nodes[child].tokens {
+                    allowed[tok as usize] = true;
+                }
+                self.collect(child, &next, allowed);
+            }
+        }
+    }
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        let line = self.src[..self.pos.min(self.src.len())].iter().filter(|c| **c == '\n').count() + 1;
+        Err(format!("grammar line {line}: {msg}"))
+    }
+
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        self.rules.push(None);
+        self.names.insert(name.to_string(), self.rules.len() - 1);
+        self.rules.len() - 1
+    }
+
+    fn new_rule(&mut self) -> usize {
+        self.rules.push(None);
+        self.rules.len() - 1
+    }
+
+    fn parse_grammar(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            if !self.src[self.pos..].starts_with(&[':', ':', '=']) {
+                return self.error(&format!("expected `::=` after `{name}`"));
+            }
+            self.pos += 3;
+            let alts = self.parse_alternatives(false)?;
+            if self.peek() == Some(')') {
+                return self.error("unbalanced `)`");
+            }
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                return self.error(&format!("rule `{name}` defined twice"));
+            }
+            self.rules[id] = Some(alts);
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return self.error("expected a rule name");
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn parse_alternatives(&mut self, nested: bool) -> Result<Vec<Vec<Element>>, String> {
+        let mut alts = vec![self.parse_sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_sequence(nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<Vec<Element>, String> {
+        let mut seq = Vec::new();
+        loop {
+            self.skip_space(nested);
+            if self.peek() == Some('\n') {
+                // a top-level rule ends with its line, unless the next line starts with `|`
+                let end = self.pos;
+                self.skip_space(true);
+                if self.peek() != Some('|') {
+                    self.pos = end;
+                }
+                return Ok(seq);
+            }
+            let item = match self.peek() {
+                None | Some('|') | Some(')') => return Ok(seq),
+                Some('"') => {
+                    self.pos += 1;
+                    let mut item = Vec::new();
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char()?;
+                        item.push(Element::Chars { ranges: vec![(c, c)], negated: false });
+                    }
+                    self.pos += 1;
+                    item
+                }
+                Some('[') => vec![self.parse_class()?],
+                Some('.') => {
+                    self.pos += 1;
+                    vec![Element::Chars { ranges: Vec::new(), negated: true }]
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let alts = self.parse_alternatives(true)?;
+                    self.skip_space(true);
+                    if self.peek() != Some(')') {
+                        return self.error("expected `)`");
+                    }
+                    self.pos += 1;
+                    let id = self.new_rule();
+                    self.rules[id] = Some(alts);
+                    vec![Element::Rule(id)]
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.parse_name()?;
+                    vec![Element::Rule(self.rule_id(&name))]
+                }
+                Some(c) => return self.error(&format!("unexpected `{c}`")),
+            };
+
+            // repetitions become helper rules: x* => r ::= x r | "", x? => r ::= x | "",
+            // x{m,n} => m copies of x followed by n - m nested optional ones
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => self.parse_braces()?,
+                _ => (1, Some(1)),
+            };
+            if min != 1 || max != Some(1) {
+                self.pos += 1;
+            }
+            for _ in 0..min {
+                seq.extend(item.iter().cloned());
+            }
+            match max {
+                None => {
+                    let id = self.new_rule();
+                    let mut repeat = item;
+                    repeat.push(Element::Rule(id));
+                    self.rules[id] = Some(vec![repeat, Vec::new()]);
+                    seq.push(Element::Rule(id));
+                }
+                Some(max) => {
+                    let mut tail = None;
+                    for _ in min..max {
+                        let id = self.new_rule();
+                        let mut repeat = item.clone();
+                        repeat.extend(tail.map(Element::Rule));
+                        self.rules[id] = Some(vec![repeat, Vec::new()]);
+                        tail = Some(id);
+                    }
+                    seq.extend(tail.map(Element::Rule));
+                }
+            }
+        }
+    }
+
+    // {m}, {m,} or {m,n}, leaving pos on the closing brace
+    fn parse_braces(&mut self) -> Result<(usize, Option<usize>), String> {
+        let number = |p: &mut Self| {
+            p.pos += 1;
+            p.skip_space(false);
+            let start = p.pos;
+            while matches!(p.peek(), Some(c) if c.is_ascii_digit()) {
+                p.pos += 1;
+            }
+            let digits: String = p.src[start..p.pos].iter().collect();
+            p.skip_space(false);
+            digits.parse::<usize>().ok()
+        };
+        let min = number(self);
+        let max = match self.peek() {
+            Some(',') => number(self),
+            _ => min,
+        };
+        match (min, self.peek()) {
+            (Some(min), Some('}')) if max.is_none_or(|max| max >= min) => Ok((min, max)),
+            _ => self.error("bad repetition `{m,n}`"),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Element, String> {
+        self.pos += 1;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        while self.peek() != Some(']') {
+            let lo = self.parse_char()?;
+            let mut hi = lo;
+            if self.peek() == Some('-') && self.src.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                hi = self.parse_char()?;
+            }
+            ranges.push((lo, hi));
+        }
+        self.pos += 1;
+        Ok(Element::Chars { ranges, negated })
+    }
+
+    // One char of a literal or class, resolving escapes
+    fn parse_char(&mut self) -> Result<char, String> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("unterminated literal or class"),
+        };
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let e = match self.peek() {
+            Some(e) => e,
+            None => return self.error("unterminated escape"),
+        };
+        self.pos += 1;
+        let hex_len = match e {
+            'n' => return Ok('\n'),
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return Ok(e),
+        };
+        let end = (self.pos + hex_len).min(self.src.len());
+        let hex: String = self.src[self.pos..end].iter().collect();
+        self.pos = end;
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) => Ok(c),
+            None => self.error(&format!("bad escape `\\{e}{hex}`")),
+        }
+    }
+}
+
+// The ways of continuing from pos: the positions to push in its place so the top is a Chars
+// element, or an empty stack when its sequence can finish without consuming a char. Computed
+// once per (rule, alternative, element) into memo, so expand only copies stacks.
+fn closure(rules: &[Vec<Vec<Element>>], pos: Pos, memo: &mut HashMap<Pos, Vec<Stack>>) {
+    if memo.contains_key(&pos) {
+        return;
+    }
+    let (r, a, i) = pos;
+    let seq = &rules[r][a];
+    let stacks = match seq.get(i) {
+        None => vec![Vec::new()],
+        Some(Element::Chars { .. }) => vec![vec![pos]],
+        Some(&Element::Rule(id)) => {
+            let next = (r, a, i + 1);
+            let mut stacks = Vec::new();
+            for alt in 0..rules[id].len() {
+                closure(rules, (id, alt, 0), memo);
+                for top in memo[&(id, alt, 0)].clone() {
+                    if top.is_empty() {
+                        // only a rule that can finish empty continues with next right away;
+                        // otherwise next waits on the stack (x* => r ::= x r would recurse)
+                        closure(rules, next, memo);
+                        stacks.extend(memo[&next].iter().cloned());
+                    } else {
+                        // skip positions at the end of a sequence so right recursion stays flat
+                        let mut s = if i + 1 < seq.len() { vec![next] } else { Vec::new() };
+                        s.extend(top);
+                        stacks.push(s);
+                    }
+                }
+            }
+            stacks.sort();
+            stacks.dedup();
+            stacks
+        }
+    };
+    memo.insert(pos, stacks);
+}
+
+// Left recursion (r ::= r "x") would make expand and closure loop forever
+fn check_left_recursion(rules: &[Vec<Vec<Element>>]) -> Result<(), String> {
+    let mut nullable = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for r in 0..rules.len() {
+            let is_nullable = rules[r].iter().any(|seq| {
+                seq.iter().all(|e| matches!(e, Element::Rule(id) if nullable[*id]))
+            });
+            if is_nullable && !nullable[r] {
+                nullable[r] = true;
+                changed = true;
+            }
+        }
+    }
+    // rules that can be entered before consuming any char
+    let first: Vec<Vec<usize>> = rules
+        .iter()
+        .map(|alts| {
+            let mut first = Vec::new();
+            for seq in alts {
+                for e in seq {
+                    match e {
+                        Element::Rule(id) => {
+                            first.push(*id);
+                            if !nullable[*id] {
+                                break;
+                            }
+                        }
+                        Element::Chars { .. } => break,
+                    }
+                }
+            }
+            first
+        })
+        .collect();
+    for r in 0..rules.len() {
+        let mut seen = vec![false; rules.len()];
+        let mut todo = first[r].clone();
+        while let Some(id) = todo.pop() {
+            if id == r {
+                return Err("grammar is left-recursive".into());
+            }
+            if !seen[id] {
+                seen[id] = true;
+                todo.extend_from_slice(&first[id]);
+            }
+        }
+    }
+    Ok(())
+}
+
+const JSON_RULES: &str = r#"
+value   ::= object | array | string | number | boolean | null
+object  ::= "{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}"
+array   ::= "[" ws ( value ws ( "," ws value ws )* )? "]"
+string  ::= "\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\""
+number  ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+integer ::= "-"? ( "0" | [1-9] [0-9]* )
+boolean ::= "true" | "false"
+null    ::= "null"
+ws      ::= | " " | "\n" [ \t]{0,20}
+"#;
+
+// Translates the common JSON schema keywords (type, properties, required, items, enum,
+// const, anyOf/oneOf) to GBNF. Objects list their required properties first, then any of
+// the optional ones, each group in key order.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String, String> {
+    let mut rules = Vec::new();
+    let root = schema_rule(schema, "schema", &mut rules)?;
+    let mut gbnf = format!("root ::= ws {root}\n");
+    for (name, body) in rules {
+        gbnf += &format!("{name} ::= {body}\n");
+    }
+    Ok(gbnf + JSON_RULES)
+}
+
+// Returns an expression for `schema`, adding named rules for its parts to `rules`
+fn schema_rule(schema: &Value, name: &str, rules: &mut Vec<(String, String)>) -> Result<String, String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok("value".into()),
+        Value::Object(schema) => schema,
+        _ => return Err(format!("unsupported schema at `{name}`")),
+    };
+    if schema.contains_key("$ref") {
+        return Err(format!("$ref is not supported (at `{name}`)"));
+    }
+    let literal = |v: &Value| gbnf_literal(&v.to_string());
+    let add = |rules: &mut Vec<(String, String)>, body: String| -> Result<String, String> {
+        rules.push((name.to_string(), body));
+        Ok(name.to_string())
+    };
+
+    if let Some(value) = schema.get("const") {
+        return add(rules, literal(value));
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        let alts: Vec<String> = values.iter().map(literal).collect();
+        return add(rules, alts.join(" | "));
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(options)) = schema.get(key) {
+            let alts = options
+                .iter()
+                .enumerate()
+                .map(|(i, option)| schema_rule(option, &format!("{name}-{i}"), rules))
+                .collect::<Result<Vec<_>, _>>()?;
+            return add(rules, alts.join(" | "));
+        }
+    }
+    let body = match schema.get("type") {
+        None => "value".to_string(),
+        Some(Value::Array(types)) => {
+            let alts = types
+                .iter()
+                .map(|t| {
+                    let mut single = schema.clone();
+                    single.insert("type".into(), t.clone());
+                    let t = t.as_str().unwrap_or("value");
+                    schema_rule(&Value::Object(single), &format!("{name}-{t}"), rules)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            alts.join(" | ")
+        }
+        Some(Value::String(t)) => match t.as_str() {
+            "object" => match schema.get("properties") {
+                Some(Value::Object(properties)) => {
+                    let required: Vec<&str> = match schema.get("required") {
+                        Some(Value::Array(required)) => required.iter().filter_map(|r| r.as_str()).collect(),
+                        _ => Vec::new(),
+                    };
+                    let mut pairs = Vec::new();
+                    let mut optional = Vec::new();
+                    for (key, property) in properties {
+                        let value = schema_rule(property, &format!("{name}-{}", rule_name(key)), rules)?;
+                        let pair = format!("{} ws \":\" ws {value}", gbnf_literal(&Value::from(key.as_str()).to_string()));
+                        match required.contains(&key.as_str()) {
+                            true => pairs.push(pair),
+                            false => optional.push(pair),
+                        }
+                    }
+                    object_body(&pairs, &optional)
+                }
+                _ => "object".to_string(),
+            },
+            "array" => match schema.get("items") {
+                Some(items) => {
+                    let item = schema_rule(items, &format!("{name}-item"), rules)?;
+                    format!("\"[\" ws ( {item} ws ( \",\" ws {item} ws )* )? \"]\"")
+                }
+                None => "array".to_string(),
+            },
+            "string" | "number" | "integer" | "boolean" | "null" => t.clone(),
+            other => return Err(format!("unknown type `{other}` at `{name}`")),
+        },
+        Some(_) => return Err(format!("bad type at `{name}`")),
+    };
+    add(rules, body)
+}
+
+// Required pairs in order, followed by any subset of the optional ones in order
+fn object_body(required: &[String], optional: &[String]) -> String {
+    let rest = |from: usize| -> String {
+        optional[from..].iter().map(|pair| format!(" ( \",\" ws {pair} ws )?")).collect()
+    };
+    let inner = if !required.is_empty() {
+        let pairs: Vec<String> = required.iter().map(|pair| format!("{pair} ws")).collect();
+        format!("{}{}", pairs.join(" \",\" ws "), rest(0))
+    } else if !optional.is_empty() {
+        let alts: Vec<String> = (0..optional.len())
+            .map(|i| format!("{} ws{}", optional[i], rest(i + 1)))
+            .collect();
+        format!("( {} )?", alts.join(" | "))
+    } else {
+        String::new()
+    };
+    format!("\"{{\" ws {inner} \"}}\"")
+}
+
+fn gbnf_literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn rule_name(key: &str) -> String {
+    key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect()
+}
+
+#[test]
+fn test_grammar_accepts() {
+    let grammar = Grammar::parse(
+        r#"
+        root ::= "[" num ("," num)* "]"
+               | "none"   # alternative on the next line
+        num  ::= "-"? [0-9]{1,3}
+        "#,
+    )
+    .unwrap();
+    assert!(grammar.accepts("[1,-23,4]"));
+    assert!(grammar.accepts("none"));
+    assert!(!grammar.accepts("[1,]"));
+    assert!(!grammar.accepts("[1"));
+    assert!(!grammar.accepts("[1234]"));
+    assert!(Grammar::parse("root ::= root \"a\" | \"b\"").is_err());
+    assert!(Grammar::parse("root ::= missing").is_err());
+}
+
+#[test]
+fn test_json_schema_grammar() {
+    let schema: Value = serde_json::from_str(
+        r#"{
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } }
+            },
+            "required": ["name"]
+        }"#,
+    )
+    .unwrap();
+    let grammar = Grammar::from_json_schema(&schema).unwrap();
+    assert!(grammar.accepts(r#"{"name": "张三"}"#));
+    assert!(grammar.accepts(r#"{ "name": "x\"y", "age": 3, "tags": ["a","b"] }"#));
+    assert!(grammar.accepts(r#"{"name": "", "tags": []}"#));
+    assert!(!grammar.accepts(r#"{"age": 3}"#));
+    assert!(!grammar.accepts(r#"{"name": "x", "age": 1.5}"#));
+    assert!(!grammar.accepts(r#"{"name": "x", "tags": ["c"]}"#));
+}
+
+#[test]
+fn test_grammar_constraint_mask() {
+    let grammar = Grammar::parse(r#"root ::= "ab" | "abc" | "é""#).unwrap();
+    let vocab: Vec<Vec<u8>> = vec![
+        b"a".to_vec(),
+        b"ab".to_vec(),
+        b"b".to_vec(),
+        b"c".to_vec(),
+        vec![0xc3],
+        vec![0xa9],
+        Vec::new(), // eos
+    ];
+    let trie = TokenTrie::new(vocab);
+    let mut constraint = GrammarConstraint::new(&grammar, &trie, 6);
+    let allowed = |constraint: &mut GrammarConstraint| {
+        let mut logits = Tensor::<f32>::default(&[7]);
+        constraint.apply(&mut logits);
+        logits.data().iter().enumerate().filter(|(_, l)| l.is_finite()).map(|(i, _)| i).collect::<Vec<_>>()
+    };
+    assert_eq!(allowed(&mut constraint), vec![0, 1, 4]);
+    assert!(constraint.accept(2).is_err());
+    constraint.accept(1).unwrap();
+    assert_eq!(allowed(&mut constraint), vec![3, 6]);
+    constraint.accept(3).unwrap();
+    assert_eq!(allowed(&mut constraint), vec![6]);
+
+    let mut constraint = GrammarConstraint::new(&grammar, &trie, 6);
+    constraint.accept(4).unwrap();
+    assert_eq!(allowed(&mut constraint), vec![5]);
+}
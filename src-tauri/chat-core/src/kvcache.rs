@@ -0,0 +1,281 @@
+use std::any::Any;
+
+use crate::tensor::Tensor;
+// Keys and values of every past position. A ring cache (sliding-window models) keeps only the
+// latest `max_seq_len` positions, position p in row p % max_seq_len, so its memory stays
+// bounded however long the sequence grows.
+#[derive(Clone)]
+pub struct KVCache<T> {
+    k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
+    v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
+    max_seq_len: usize,      // rows per layer
+    dim: usize,
+    length: usize, // length of the current sequence
+    window: Option<usize>, // attention window of a ring cache
+    written: usize, // positions below this hold valid data (reset_len may move back up to it)
+    workspace: Workspace, // scratch buffers the model reuses between forward passes
+}
+
+// Scratch space a model keeps with the cache it decodes into, so a session allocates its
+// buffers once. Its content is only meaningful during a pass, so copies start without one.
+#[derive(Default)]
+struct Workspace(Option<Box<dyn Any + Send + Sync>>);
+
+impl Clone for Workspace {
+    fn clone(&self) -> Self {
+        Workspace(None)
+    }
+}
+
+impl<T: Default + Copy> KVCache<T> {
+    pub fn new(n_layers: usize, max_seq_len: usize, dim: usize, init_len: usize) -> Self {
+        KVCache {
+            k_cache: (0..n_layers)
+                .map(|_| Tensor::default(&[max_seq_len, dim]))
+                .collect(),
+            v_cache: (0..n_layers)
+                .map(|_| Tensor::default(&[max_seq_len, dim]))
+                .collect(),
+            max_seq_len,
+            dim,
+            length: init_len,
+            window: None,
+            written: init_len,
+            workspace: Workspace::default(),
+        }
+    }
+
+    // A ring cache for attention over the last `window` positions. It holds two windows, so a
+    // prompt can be written a window at a time without overwriting keys it still attends to.
+    pub fn ring(n_layers: usize, window: usize, dim: usize) -> Self {
+        KVCache { window: Some(window), ..Self::new(n_layers, 2 * window, dim, 0) }
+    }
+
+    // Stores the keys and values of positions start..len(), (len() - start, dim) each, which
+    // must not wrap around the ring (see max_chunk)
+    pub fn write(&mut self, layer: usize, start: usize, k: &Tensor<T>, v: &Tensor<T>) {
+        let n = self.length - start;
+        let row = self.row(start, n);
+        for (cache, x) in [(&mut self.k_cache[layer], k), (&mut self.v_cache[layer], v)] {
+            assert!(x.size() == n * self.dim, "{:?} rows for {n} positions of width {}", x.shape(), self.dim);
+            cache.data_mut()[row * self.dim..][..n * self.dim].copy_from_slice(x.data());
+        }
+    }
+
+    fn row(&self, start: usize, n: usize) -> usize {
+        if self.window.is_none() {
+            return start;
+        }
+        let row = start % self.max_seq_len;
+        assert!(row + n <= self.max_seq_len, "positions {start}..{} wrap around the ring", start + n);
+        row
+    }
+
+    // Every row currently held, (rows, dim) for keys and values, with positions() telling which
+    // position each row belongs to
+    pub fn keys(&self, layer: usize) -> Tensor<T> {
+        self.k_cache[layer].slice(0, &[self.rows(), self.dim])
+    }
+
+    pub fn values(&self, layer: usize) -> Tensor<T> {
+        self.v_cache[layer].slice(0, &[self.rows(), self.dim])
+    }
+
+    // Number of rows keys() / values() return
+    pub fn rows(&self) -> usize {
+        self.length.min(self.max_seq_len)
+    }
+
+    pub fn layers(&self) -> usize {
+        self.k_cache.len()
+    }
+
+    // Most rows the cache can hold
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // Position stored in a row returned by keys() / values(): the latest position
+    // p < length with p % max_seq_len == row
+    pub fn position(&self, row: usize) -> usize {
+        row + (self.length - 1 - row) / self.max_seq_len * self.max_seq_len
+    }
+
+    pub fn positions(&self) -> Vec<usize> {
+        (0..self.rows()).map(|row| self.position(row)).collect()
+    }
+
+    // Takes the scratch buffers a model left with put_workspace, if they are of type B
+    pub fn take_workspace<B: Any + Send + Sync>(&mut self) -> Option<Box<B>> {
+        self.workspace.0.take()?.downcast().ok()
+    }
+
+    pub fn put_workspace<B: Any + Send + Sync>(&mut self, buffers: Box<B>) {
+        self.workspace.0 = Some(buffers);
+    }
+
+    // How many positions the next forward pass may add at once: a ring cache must neither wrap
+    // within one write nor overwrite keys the new positions still attend to
+    pub fn max_chunk(&self) -> usize {
+        match self.window {
+            None => usize::MAX,
+            Some(window) => {
+                let to_end = self.max_seq_len - self.length % self.max_seq_len;
+                to_end.min(self.max_seq_len + 1 - window)
+            }
+        }
+    }
+
+    // Whether reset_len(len) leaves the cache able to continue from len: every position the
+    // next token attends to must still be held, not overwritten by later ones
+    pub fn retains(&self, len: usize) -> bool {
+        if len > self.written {
+            return false;
+        }
+        match self.window {
+            None => true,
+            Some(window) => {
+                let needed = (len + 1).saturating_sub(window);
+                needed >= len || needed >= self.written.saturating_sub(self.max_seq_len)
+            }
+        }
+    }
+
+    // A copy that can keep growing independently (beam search, alternative answers). The
+    // buffers are copy-on-write, so a layer is only copied when either cache first writes to it;
+    // clone() does the same.
+    pub fn fork(&self) -> Self {
+        self.clone()
+    }
+
+    // Makes this cache hold other's sequence by copying the rows of positions start..other.len(),
+    // for caches that already agree on every position before start (beams sharing a prompt)
+    pub fn copy_from(&mut self, other: &Self, start: usize) {
+        assert!(self.max_seq_len == other.max_seq_len && self.dim == other.dim && self.window == other.window);
+        let (dim, rows) = (self.dim, self.max_seq_len);
+        let first = start.max(other.length.saturating_sub(rows));
+        let layers = self.k_cache.iter_mut().zip(&other.k_cache).chain(self.v_cache.iter_mut().zip(&other.v_cache));
+        for (to, from) in layers {
+            let (to, from) = (to.data_mut(), from.data());
+            for p in first..other.length {
+                let row = p % rows * dim;
+                to[row..][..dim].copy_from_slice(&from[row..][..dim]);
+            }
+        }
+        self.length = other.length;
+        self.written = other.length;
+    }
+
+    pub fn increment(&mut self, seq_len: usize) {
+        self.length += seq_len;
+        self.written = self.length;
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    // Moves the end of the sequence to new_len, which retains() must allow
+    pub fn reset_len(&mut self, new_len: usize) {
+        assert!(self.retains(new_len), "cache no longer holds what position {new_len} attends to");
+        self.length = new_len;
+        // for layer in 0..self.k_cache.len() {
+        //     self.k_cache[layer].shrink(new_len);   
+        // }
+
+        // for layer in 0..self.k_cache.len() {
+        //     self.k_cache[layer].shrink(new_len);   
+        // }
+    } 
+}
+
+
+#[test]
+fn test_ring_cache() {
+    let mut cache = KVCache::<f32>::ring(1, 3, 1);
+    assert_eq!(cache.max_chunk(), 4);
+    let write = |cache: &mut KVCache<f32>, n: usize| {
+        let start = cache.len();
+        cache.increment(n);
+        let rows = Tensor::new((start..start + n).map(|p| p as f32).collect(), &[n, 1]);
+        cache.write(0, start, &rows, &rows);
+    };
+    write(&mut cache, 4);
+    assert_eq!(cache.max_chunk(), 2); // two rows left before the ring wraps
+    write(&mut cache, 2);
+    write(&mut cache, 3); // positions 6..9 in rows 0..3
+    assert_eq!(cache.positions(), vec![6, 7, 8, 3, 4, 5]);
+    assert_eq!(cache.keys(0).data(), &[6., 7., 8., 3., 4., 5.]);
+    // positions 3..9 are held: continuing at 5 needs 3 and 4, continuing at 4 needs 2 as well
+    assert!(cache.retains(9) && cache.retains(6) && cache.retains(5) && cache.retains(0));
+    assert!(!cache.retains(4) && !cache.retains(10));
+    cache.reset_len(6);
+    assert!(cache.retains(9)); // nothing written yet, the rolled back positions are still there
+    write(&mut cache, 1);
+    assert!(!cache.retains(9));
+    // rows 1 and 2 still hold the rolled back 7 and 8, outside the window of anything after 6
+    assert_eq!(cache.positions(), vec![6, 1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn test_fork_copy_on_write() {
+    let mut cache = KVCache::<f32>::new(1, 4, 2, 0);
+    cache.increment(1);
+    cache.write(0, 0, &Tensor::new(vec![1., 2.], &[1, 2]), &Tensor::new(vec![3., 4.], &[1, 2]));
+    let keys = cache.keys(0);
+    let mut fork = cache.fork();
+    fork.increment(1);
+    fork.write(0, 1, &Tensor::new(vec![5., 6.], &[1, 2]), &Tensor::new(vec![7., 8.], &[1, 2]));
+    cache.increment(1);
+    cache.write(0, 1, &Tensor::new(vec![9., 9.], &[1, 2]), &Tensor::new(vec![9., 9.], &[1, 2]));
+    // neither cache nor the view taken before the writes sees the other's rows
+    assert_eq!(fork.keys(0).data(), [1., 2., 5., 6.]);
+    assert_eq!(cache.keys(0).data(), [1., 2., 9., 9.]);
+    assert_eq!(keys.data(), [1., 2.]);
+    assert_eq!(fork.values(0).data(), [3., 4., 7., 8.]);
+}
+
+#[test]
+fn test_copy_from() {
+    let write = |cache: &mut KVCache<f32>, values: &[f32]| {
+        for &x in values {
+            let start = cache.len();
+            cache.increment(1);
+            let row = Tensor::new(vec![x], &[1, 1]);
+            cache.write(0, start, &row, &row);
+        }
+    };
+    // two sequences sharing the prompt 1, 2
+    let (mut a, mut b) = (KVCache::<f32>::new(1, 8, 1, 0), KVCache::<f32>::new(1, 8, 1, 0));
+    write(&mut a, &[1., 2., 3., 4., 5.]);
+    write(&mut b, &[1., 2., 7., 8.]);
+    b.copy_from(&a, 2);
+    assert_eq!(b.len(), 5);
+    assert_eq!(b.keys(0).data(), a.keys(0).data());
+    assert_eq!(b.values(0).data(), [1., 2., 3., 4., 5.]);
+    // a ring only copies the rows still held
+    let (mut a, mut b) = (KVCache::<f32>::ring(1, 2, 1), KVCache::<f32>::ring(1, 2, 1));
+    write(&mut a, &[1., 2., 3., 4., 5., 6.]);
+    write(&mut b, &[1., 2., 9., 9., 9., 9.]);
+    b.copy_from(&a, 2);
+    assert_eq!(b.keys(0).data(), a.keys(0).data());
+    assert_eq!(b.positions(), a.positions());
+}
+
+#[test]
+fn test_workspace() {
+    let mut cache = KVCache::<f32>::new(1, 2, 1, 0);
+    cache.put_workspace(Box::new(vec![1u8; 3]));
+    // copies start without the scratch buffers
+    let mut fork = cache.fork();
+    assert!(fork.take_workspace::<Vec<u8>>().is_none());
+    assert_eq!(cache.take_workspace::<Vec<u8>>().as_deref(), Some(&vec![1u8; 3]));
+    // taking them as another type drops them
+    cache.put_workspace(Box::new(vec![1u8; 3]));
+    assert!(cache.take_workspace::<String>().is_none());
+    assert!(cache.take_workspace::<Vec<u8>>().is_none());
+}
@@ -0,0 +1,16 @@
+// Inference core: model loading, KV cache, sampling, prompt templates and constrained decoding.
+// Used by the Tauri app and by the `chat-cli` terminal client.
+pub mod config;
+pub mod dtype;
+pub mod eval;
+pub mod generate;
+pub mod grammar;
+pub mod kvcache;
+pub mod llama;
+pub mod model;
+pub mod operators;
+pub mod params;
+pub mod rope;
+pub mod tensor;
+pub mod template;
+pub mod vocab;
@@ -0,0 +1,650 @@
+// The Llama decoder and the families sharing its layout (Mistral, Qwen2, Mixtral, Qwen2-MoE):
+// pre-norm attention with rope and grouped k/v heads, then a SwiGLU or mixture-of-experts MLP.
+use std::fs::File;
+use std::path::Path;
+use std::vec;
+
+use crate::config::{Architecture, LlamaConfigJson};
+use crate::dtype::Float;
+use crate::kvcache::KVCache;
+use crate::model::ModelArchitecture;
+use crate::operators::{self as OP, matmul_transb, rms_norm, silu};
+use crate::params::{Expert, LLamaParams, MoeParams};
+use crate::rope::RopeTable;
+use crate::tensor::Tensor;
+use safetensors::SafeTensors;
+
+pub struct Llama<T> {
+    vocab: usize,           // vocab size
+    n_layers: usize,        // number of layers
+    n_q_h: usize,           // number of heads for q
+    n_kv_h: usize,          // number of heads for k and v
+    d: usize,               // dimension of hidden states
+    dqkv: usize,            // length of a single q, k, or v vector
+    di: usize,              // dimension of intermediate states
+    eps: f32,               // epsilon for RMS normalization
+    rope: RopeTable,        // cos/sin of every position, from rope_theta and rope_scaling
+    max_seq_len: usize,     // maximum sequence length
+    sliding_window: Option<usize>, // each position attends to at most this many positions
+    n_experts_per_tok: usize, // experts every token is routed to in sparse layers
+    norm_topk_prob: bool,     // rescale the chosen experts' weights to sum to 1
+    params: LLamaParams<T>, // trained weights of this model
+    eos_token_id: u32,      // end token id
+    architecture: Architecture,
+}
+
+impl<T: Float> Llama<T> {
+    pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Self {
+        let config = File::open(model_dir.as_ref().join("config.json")).unwrap();
+        let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
+        let model_file = std::fs::read(model_dir.as_ref().join("model.safetensors")).unwrap();
+        let safetensor = SafeTensors::deserialize(&model_file).unwrap();
+        Self::from_config(config, &safetensor)
+    }
+
+    pub fn from_config(config: LlamaConfigJson, safetensor: &SafeTensors) -> Self {
+        let params = LLamaParams::from_safetensors(safetensor, &config);
+
+        Self {
+            vocab: config.vocab_size,
+            n_layers: config.num_hidden_layers,
+            n_q_h: config.num_attention_heads,
+            n_kv_h: config.num_key_value_heads,
+            d: config.hidden_size,
+            dqkv: config.hidden_size / config.num_attention_heads,
+            di: config.intermediate_size,
+            eps: config.rms_norm_eps,
+            rope: RopeTable::new(&config),
+            max_seq_len: config.max_position_embeddings,
+            sliding_window: config.sliding_window(),
+            n_experts_per_tok: config.num_experts_per_tok,
+            norm_topk_prob: config.norm_topk_prob(),
+            params,
+            eos_token_id: config.eos_token_id,
+            architecture: config.architecture(),
+        }
+    }
+
+    pub fn architecture(&self) -> Architecture {
+        self.architecture
+    }
+
+    // A ring cache of the attention window for sliding-window models, when the window is
+    // shorter than len; otherwise a full-length cache
+    pub fn new_cache(&self, len: usize) -> KVCache<T> {
+        match self.sliding_window {
+            Some(window) if window < len => KVCache::ring(self.n_layers, window, self.n_kv_h * self.dqkv),
+            _ => KVCache::new(self.n_layers, len, self.n_kv_h * self.dqkv, 0),
+        }
+    }
+
+    // Final hidden states (seq_len, d) of input. The returned tensors share the cache's scratch
+    // buffers until the next pass writes them.
+    pub fn hidden_states(&self, input: &Tensor<u32>, cache: &mut KVCache<T>) -> Tensor<T> {
+        let mut buf = self.buffers(cache);
+        self.decode_layers(input, cache, &mut buf);
+        let Buffers { residual, out, .. } = &mut *buf;
+        out.reuse(residual.shape());
+        OP::rms_norm(out, residual, &self.params.rms_out_w, T::from_f32(self.eps));
+        let hidden_states = out.clone();
+        cache.put_workspace(buf);
+        hidden_states
+    }
+
+    // Logits (rows, vocab) of hidden states (rows, d)
+    pub fn logits(&self, hidden_states: &Tensor<T>, cache: &mut KVCache<T>) -> Tensor<T> {
+        let mut buf = self.buffers(cache);
+        // Callers usually still hold the previous logits while asking for the next ones, so two
+        // buffers take turns; writing one still shared would copy it first
+        buf.logits.swap(0, 1);
+        let logits = &mut buf.logits[0];
+        logits.reuse(&[hidden_states.shape()[0], self.vocab]);
+        OP::matmul_transb(logits, T::ZERO, hidden_states, &self.params.lm_head, T::ONE);
+        let logits = logits.clone();
+        cache.put_workspace(buf);
+        logits
+    }
+
+    // The scratch buffers kept in the cache, or new ones for a cache that has none yet
+    fn buffers(&self, cache: &mut KVCache<T>) -> Box<Buffers<T>> {
+        cache.take_workspace().unwrap_or_else(|| {
+            Box::new(Buffers {
+                residual: Tensor::default(&[1, self.d]),
+                hidden_states: Tensor::default(&[1, self.d]),
+                q: Tensor::default(&[1, self.n_q_h * self.dqkv]),
+                k: Tensor::default(&[1, self.n_kv_h * self.dqkv]),
+                v: Tensor::default(&[1, self.n_kv_h * self.dqkv]),
+                gate: Tensor::default(&[1, self.di]),
+                up: Tensor::default(&[1, self.di]),
+                out: Tensor::default(&[1, self.d]),
+                logits: [Tensor::default(&[1, self.vocab]), Tensor::default(&[1, self.vocab])],
+            })
+        })
+    }
+
+    // Runs the decoder layers, leaving the residual stream (seq_len, d) before the output norm
+    // in buf.residual. Inputs longer than a ring cache can take in one pass go through in chunks.
+    fn decode_layers(&self, input: &Tensor<u32>, cache: &mut KVCache<T>, buf: &mut Buffers<T>) {
+        let seq_len = input.size();
+        if seq_len <= cache.max_chunk() {
+            return self.decode_chunk(input, cache, buf);
+        }
+        let mut residual = Vec::with_capacity(seq_len * self.d);
+        let mut start = 0;
+        while start < seq_len {
+            let n = cache.max_chunk().min(seq_len - start);
+            self.decode_chunk(&input.slice(start, &[n]), cache, buf);
+            residual.extend_from_slice(buf.residual.data());
+            start += n;
+        }
+        buf.residual = Tensor::new(residual, &[seq_len, self.d]);
+    }
+
+    fn decode_chunk(&self, input: &Tensor<u32>, cache: &mut KVCache<T>, buf: &mut Buffers<T>) {
+        let seq_len = input.size();
+        let past_seq_len = cache.len();
+        cache.increment(seq_len);
+        let eps = T::from_f32(self.eps);
+        //println!("past_seq_len： {past_seq_len}");
+        // Buffers reused across passes, only reallocated when seq_len outgrows them
+        let Buffers { residual, hidden_states, q: q_buf, k: k_buf, v: v_buf, gate: gate_buf, up: up_buf, .. } = buf;
+        residual.reuse(&[seq_len, self.d]);
+        hidden_states.reuse(&[seq_len, self.d]);
+        gate_buf.reuse(&[seq_len, self.di]);
+        up_buf.reuse(&[seq_len, self.di]);
+
+        // Computation Starts Here
+        // Embedding lookup
+        OP::gather(residual, input, &self.params.embedding_table);
+
+        for layer in 0..self.n_layers {
+            OP::rms_norm(
+                hidden_states,
+                residual,
+                &self.params.rms_att_w[layer],
+                eps,
+            );
+            q_buf.reuse(&[seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
+            k_buf.reuse(&[seq_len, self.n_kv_h * self.dqkv]); // (seq, n_kv_h * dqkv)
+            v_buf.reuse(&[seq_len, self.n_kv_h * self.dqkv]); // (seq, n_kv_h * dqkv)
+            let (q, k, v) = (&mut *q_buf, &mut *k_buf, &mut *v_buf);
+            OP::matmul_transb(q, T::ZERO, hidden_states, &self.params.wq[layer], T::ONE);
+            OP::matmul_transb(k, T::ZERO, hidden_states, &self.params.wk[layer], T::ONE);
+            OP::matmul_transb(v, T::ZERO, hidden_states, &self.params.wv[layer], T::ONE);
+            // Qwen2 偏置加在投影之后、rope 之前
+            let biases = [&self.params.bq[layer], &self.params.bk[layer], &self.params.bv[layer]];
+            for (y, bias) in [&mut *q, &mut *k, &mut *v].into_iter().zip(biases) {
+                if let Some(bias) = bias {
+                    OP::add_bias(y, bias);
+                }
+            }
+            OP::rope(
+                q.reshape(&[seq_len, self.n_q_h, self.dqkv]),
+                past_seq_len,
+                &self.rope,
+            );
+            OP::rope(
+                k.reshape(&[seq_len, self.n_kv_h, self.dqkv]),
+                past_seq_len,
+                &self.rope,
+            );
+
+            cache.write(layer, past_seq_len, k, v);
+            let full_k = &cache.keys(layer); // (total_seq, n_kv_h * dqkv)
+            let full_v = &cache.values(layer); // (total_seq, n_kv_h * dqkv)
+
+           // todo!("self_attention(...)");
+            let mask = Mask { start_pos: past_seq_len, cache, window: self.sliding_window };
+            self_attention(hidden_states, q.reshape(&[seq_len, self.n_q_h * self.dqkv]), full_k, full_v, &mask, self.n_kv_h, self.dqkv);
+            
+            OP::matmul_transb(residual, T::ONE, hidden_states, &self.params.wo[layer], T::ONE);  //输入乘以V
+           
+           
+          // residual.print();
+           // todo!("down_proj matmul and add residual");
+            match &self.params.moe[layer] {
+                Some(experts) => moe(residual, hidden_states, experts, &self.params.rms_ffn_w[layer], eps, self.n_experts_per_tok, self.norm_topk_prob),
+                None => {
+                    let buf = MlpBuffers { hidden_states: &mut *hidden_states, gate: &mut *gate_buf, up: &mut *up_buf };
+                    mlp(residual, buf, &self.params.w_up[layer], &self.params.w_down[layer], &self.params.w_gate[layer], &self.params.rms_ffn_w[layer], eps)
+                }
+            }
+            //todo!("mlp(...)");
+        }
+    }
+}
+
+// Scratch tensors of one forward pass, kept in the cache between passes so that decoding a
+// token allocates nothing. They grow to the largest chunk seen, the max batch of the session.
+struct Buffers<T> {
+    residual: Tensor<T>,      // (seq, d)
+    hidden_states: Tensor<T>, // (seq, d)
+    q: Tensor<T>,             // (seq, n_q_h * dqkv)
+    k: Tensor<T>,             // (seq, n_kv_h * dqkv)
+    v: Tensor<T>,             // (seq, n_kv_h * dqkv)
+    gate: Tensor<T>,          // (seq, di)
+    up: Tensor<T>,            // (seq, di)
+    out: Tensor<T>,           // (seq, d), after the output norm
+    logits: [Tensor<T>; 2],   // (rows, vocab), alternately, see logits()
+}
+
+impl ModelArchitecture for Llama<f32> {
+    fn load(config: serde_json::Value, weights: &SafeTensors) -> Self {
+        Self::from_config(serde_json::from_value(config).unwrap(), weights)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.d
+    }
+
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn eos_token_id(&self) -> u32 {
+        self.eos_token_id
+    }
+
+    // Model drives the f32 instance; the generic methods below do the work
+    fn new_cache(&self, len: usize) -> KVCache<f32> {
+        Llama::new_cache(self, len)
+    }
+
+    fn hidden_states(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        Llama::hidden_states(self, input, cache)
+    }
+
+    fn logits(&self, hidden_states: &Tensor<f32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        Llama::logits(self, hidden_states, cache)
+    }
+}
+// Which cached rows a query may attend to: causal, and within the sliding window if any
+struct Mask<'a, T> {
+    start_pos: usize,         // position of the first query
+    cache: &'a KVCache<T>,    // tells the position of every k/v row
+    window: Option<usize>,
+}
+
+impl<T: Default + Copy> Mask<'_, T> {
+    fn allows(&self, seq: usize, row: usize) -> bool {
+        let (query, key) = (self.start_pos + seq, self.cache.position(row));
+        key <= query && self.window.is_none_or(|window| query - key < window)
+    }
+}
+
+fn self_attention<T: Float>(
+    hidden_states: &mut Tensor<T>, // (seq, n_kv_h * n_groups * dqkv)
+    q: &Tensor<T>,                 // (seq, n_kv_h * n_groups * dqkv) (seq,  dqkv)
+    k: &Tensor<T>,                 // (total_seq, n_kv_h * dqkv) (total_seq, dqkv)
+    v: &Tensor<T>,                 // (total_seq, n_kv_h * dqkv)
+    mask: &Mask<T>,
+    n_kv_h: usize, //多头注意力的头数
+    dqkv: usize, // embeding后词向量的大小
+) {
+    // 同一个k头会被多个q头复用, 分数、softmax 与加权求和在一个融合算子中完成, 不保存分数矩阵
+    let scale = T::ONE / T::from_f32(dqkv as f32).sqrt();
+    OP::attention(hidden_states, q, k, v, n_kv_h, scale, |seq, row| mask.allows(seq, row));
+}
+
+// Scratch tensors of the feed-forward block
+struct MlpBuffers<'a, T> {
+    hidden_states: &'a mut Tensor<T>, // (seq, d)
+    gate: &'a mut Tensor<T>,          // (seq, di)
+    up: &'a mut Tensor<T>,            // (seq, di)
+}
+
+fn mlp<T: Float>(
+    residual: &mut Tensor<T>,
+    buf: MlpBuffers<T>,
+    w_up: &Tensor<T>,
+    w_down: &Tensor<T>,
+    w_gate: &Tensor<T>,
+    rms_w: &Tensor<T>,
+    eps: T,
+) {
+    let MlpBuffers { hidden_states, gate, up } = buf;
+    //let mut hidden = Tensor::<f32>::default(residual.shape());
+    rms_norm( hidden_states, residual, rms_w, eps);
+    matmul_transb(gate, T::ZERO, hidden_states, w_gate, T::ONE);
+    matmul_transb(up, T::ZERO, hidden_states, w_up, T::ONE);
+    silu(up, gate);
+    matmul_transb(hidden_states, T::ZERO, up, w_down, T::ONE);
+    OP::add(residual, hidden_states);
+    //silu(up, &gate);
+   // matmul_transb(hi, 0.0, &hidden_states, w_up, 1.0);
+    
+}
+
+// Mixture-of-experts counterpart of mlp: every token goes through the top_k experts the router
+// weighs highest, and the shared expert if there is one. Tokens routed to the same expert are
+// batched into one matmul.
+fn moe<T: Float>(
+    residual: &mut Tensor<T>,
+    hidden_states: &mut Tensor<T>,
+    params: &MoeParams<T>,
+    rms_w: &Tensor<T>,
+    eps: T,
+    top_k: usize,
+    norm_topk_prob: bool,
+) {
+    let (seq_len, d) = (residual.shape()[0], residual.shape()[1]);
+    let n_experts = params.experts.len();
+    rms_norm(hidden_states, residual, rms_w, eps);
+    let mut router_probs = Tensor::<T>::default(&[seq_len, n_experts]);
+    matmul_transb(&mut router_probs, T::ZERO, hidden_states, &params.router, T::ONE);
+    OP::softmax(&mut router_probs);
+
+    // (token, weight) pairs routed to every expert
+    let mut routed: Vec<Vec<(u32, T)>> = vec![Vec::new(); n_experts];
+    for (token, probs) in router_probs.data().chunks(n_experts).enumerate() {
+        let probs: Vec<f32> = probs.iter().map(|p| p.to_f32()).collect();
+        let top = OP::top_n(&probs, top_k);
+        let total: f32 = if norm_topk_prob { top.iter().map(|(_, p)| p).sum() } else { 1. };
+        for (expert, p) in top {
+            routed[expert as usize].push((token as u32, T::from_f32(p / total)));
+        }
+    }
+
+    let mut out = Tensor::<T>::default(&[seq_len, d]);
+    for (expert, tokens) in params.experts.iter().zip(&routed) {
+        if tokens.is_empty() {
+            continue;
+        }
+        let mut rows = Tensor::<T>::default(&[tokens.len(), d]);
+        let indices = Tensor::new(tokens.iter().map(|&(token, _)| token).collect(), &[tokens.len()]);
+        OP::gather(&mut rows, &indices, hidden_states);
+        add_rows(&mut out, &swiglu(&rows, expert), tokens.iter().copied());
+    }
+    if let (Some(shared), Some(gate)) = (&params.shared_expert, &params.shared_expert_gate) {
+        let y = swiglu(hidden_states, shared);
+        let mut gate_logits = Tensor::<T>::default(&[seq_len, 1]);
+        matmul_transb(&mut gate_logits, T::ZERO, hidden_states, gate, T::ONE);
+        let weights = gate_logits.data().iter().map(|&g| T::ONE / (T::ONE + (-g).exp()));
+        add_rows(&mut out, &y, (0..seq_len as u32).zip(weights));
+    }
+    OP::add(residual, &out);
+}
+
+// out[token] += weight * y[i] for the i-th (token, weight) pair
+fn add_rows<T: Float>(out: &mut Tensor<T>, y: &Tensor<T>, tokens: impl Iterator<Item = (u32, T)>) {
+    let d = out.shape()[1];
+    let _out = out.data_mut();
+    for (row, (token, weight)) in y.data().chunks(d).zip(tokens) {
+        _out[token as usize * d..][..d].iter_mut().zip(row).for_each(|(o, y)| *o += weight * *y);
+    }
+}
+
+// One expert's feed-forward block on the rows of x, (m, d) -> (m, d)
+fn swiglu<T: Float>(x: &Tensor<T>, expert: &Expert<T>) -> Tensor<T> {
+    let m = x.shape()[0];
+    let di = expert.w_up.shape()[0];
+    let mut gate = Tensor::<T>::default(&[m, di]);
+    let mut up = Tensor::<T>::default(&[m, di]);
+    matmul_transb(&mut gate, T::ZERO, x, &expert.w_gate, T::ONE);
+    matmul_transb(&mut up, T::ZERO, x, &expert.w_up, T::ONE);
+    silu(&mut up, &gate);
+    let mut y = Tensor::<T>::default(&[m, x.shape()[1]]);
+    matmul_transb(&mut y, T::ZERO, &up, &expert.w_down, T::ONE);
+    y
+}
+
+#[test]
+pub fn test_mlp() {
+    let seq_len = 4;
+    let d = 2;
+    let di = 3;
+    let mut residual = Tensor::<f32>::new(vec![1., 1., 1., 1., 1., 1., 1., 1.], &[seq_len, d]);
+    let mut hidden_states = Tensor::<f32>::default(&[seq_len, d]);
+    let mut gate_buf = Tensor::<f32>::default(&[seq_len, di]);
+    let mut up_buf = Tensor::<f32>::default(&[seq_len, di]);
+    let w_up = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &[di, d]);
+    let w_down = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &[d, di]);
+    let w_gate = Tensor::<f32>::new(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &[di, d]);
+    let rms_w = Tensor::<f32>::new(vec![1., 1.], &[d]);
+    let eps = 1e-6;
+    mlp(
+        &mut residual,
+        MlpBuffers { hidden_states: &mut hidden_states, gate: &mut gate_buf, up: &mut up_buf },
+        &w_up,
+        &w_down,
+        &w_gate,
+        &rms_w,
+        eps,
+    );
+
+    assert!(residual.close_to(
+        &Tensor::<f32>::new(
+            vec![
+                1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964,
+                1.7290739
+            ],
+            &[seq_len, d]
+        ),
+        1e-3
+    ))
+}
+
+#[test]
+pub fn test_moe() {
+    let (seq_len, d) = (4, 2);
+    let weights = |data: Vec<f32>, shape: &Vec<usize>| Tensor::<f32>::new(data, shape);
+    let expert = |scale: f32| Expert {
+        w_up: weights(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![3, d]),
+        w_gate: weights(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], &vec![3, d]),
+        w_down: weights([0.1, 0.2, 0.3, 0.4, 0.5, 0.6].map(|w| w * scale).to_vec(), &vec![d, 3]),
+    };
+    let rms_w = weights(vec![1., 1.], &vec![d]);
+    let run = |params: &MoeParams<f32>, top_k: usize, norm: bool| {
+        let mut residual = weights(vec![1.; seq_len * d], &vec![seq_len, d]);
+        let mut hidden_states = Tensor::<f32>::default(&[seq_len, d]);
+        moe(&mut residual, &mut hidden_states, params, &rms_w, 1e-6, top_k, norm);
+        residual
+    };
+    // test_mlp's block as the expert the router prefers gives test_mlp's result
+    let dense = weights(vec![1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964, 1.7290739, 1.3429964, 1.7290739], &vec![seq_len, d]);
+    let params = MoeParams {
+        router: weights(vec![1., 1., -1., -1.], &vec![2, d]),
+        experts: vec![expert(1.), expert(-5.)],
+        shared_expert: None,
+        shared_expert_gate: None,
+    };
+    assert!(run(&params, 1, true).close_to(&dense, 1e-3));
+    // two identical experts with normalized weights add up to one
+    let params = MoeParams { experts: vec![expert(1.), expert(1.)], ..params };
+    assert!(run(&params, 2, true).close_to(&dense, 1e-3));
+    // a shared expert with a zero gate contributes half its output
+    let params = MoeParams { shared_expert: Some(expert(2.)), shared_expert_gate: Some(weights(vec![0., 0.], &vec![1, d])), ..params };
+    let twice = weights(dense.data().iter().map(|x| 2. * x - 1.).collect(), &vec![seq_len, d]);
+    assert!(run(&params, 2, true).close_to(&twice, 1e-3));
+}
+
+// Counts the heap allocations of the current thread, so a test can check a code path makes none.
+// Work handed to rayon runs on other threads, so such a test runs on a one-thread pool.
+#[cfg(test)]
+mod alloc_counter {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING: Counting = Counting;
+
+    pub fn count() -> usize {
+        ALLOCATIONS.with(|n| n.get())
+    }
+}
+
+// A two-layer model with deterministic weights, small enough to build in a test
+#[cfg(test)]
+fn tiny_llama<T: Float>() -> Llama<T> {
+    use safetensors::tensor::{Dtype, TensorView};
+    let (vocab, d, di, n_layers) = (32, 8, 12, 2);
+    let config: LlamaConfigJson = serde_json::from_value(serde_json::json!({
+        "bos_token_id": 1, "eos_token_id": 2, "hidden_size": d, "intermediate_size": di,
+        "max_position_embeddings": 64, "num_attention_heads": 4, "num_hidden_layers": n_layers,
+        "num_key_value_heads": 2, "vocab_size": vocab, "torch_dtype": "float32",
+        "tie_word_embeddings": false,
+    }))
+    .unwrap();
+    let mut shapes = vec![
+        ("model.embed_tokens.weight".to_string(), vec![vocab, d]),
+        ("model.norm.weight".to_string(), vec![d]),
+        ("lm_head.weight".to_string(), vec![vocab, d]),
+    ];
+    for i in 0..n_layers {
+        let layer = |name: &str, shape: Vec<usize>| (format!("model.layers.{i}.{name}.weight"), shape);
+        shapes.extend([
+            layer("input_layernorm", vec![d]),
+            layer("self_attn.q_proj", vec![d, d]),
+            layer("self_attn.k_proj", vec![d / 2, d]),
+            layer("self_attn.v_proj", vec![d / 2, d]),
+            layer("self_attn.o_proj", vec![d, d]),
+            layer("post_attention_layernorm", vec![d]),
+            layer("mlp.up_proj", vec![di, d]),
+            layer("mlp.gate_proj", vec![di, d]),
+            layer("mlp.down_proj", vec![d, di]),
+        ]);
+    }
+    let bytes: Vec<Vec<u8>> = shapes
+        .iter()
+        .map(|(_, shape)| {
+            let n: usize = shape.iter().product();
+            (0..n).flat_map(|i| ((i * 37 % 23) as f32 / 23. - 0.5).to_le_bytes()).collect()
+        })
+        .collect();
+    let views = shapes.iter().zip(&bytes).map(|((name, shape), bytes)| (name, TensorView::new(Dtype::F32, shape.clone(), bytes).unwrap()));
+    let file = safetensors::serialize(views, &None).unwrap();
+    Llama::from_config(config, &SafeTensors::deserialize(&file).unwrap())
+}
+
+#[test]
+pub fn test_decode_without_allocation() {
+    use crate::model::Model;
+    let model = Model::new(tiny_llama::<f32>());
+
+    let mut cache = model.new_cache();
+    let input = Tensor::new(vec![3], &[1]);
+    // with a single worker every parallel operator runs on the thread being counted
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    let (allocations, next, logits) = pool.install(|| {
+        let mut logits = model.forward(&Tensor::new(vec![1, 5, 7, 9], &[4]), &mut cache);
+        for _ in 0..2 {
+            logits = model.forward(&input, &mut cache);
+        }
+        // the previous logits are still alive, as in a sampling loop
+        let before = alloc_counter::count();
+        let next = model.forward(&input, &mut cache);
+        (alloc_counter::count() - before, next, logits)
+    });
+    assert_eq!(allocations, 0);
+    assert_eq!(next.shape(), logits.shape());
+}
+
+#[test]
+pub fn test_f64_matches_f32() {
+    let (single, double) = (tiny_llama::<f32>(), tiny_llama::<f64>());
+    let (mut cache32, mut cache64) = (single.new_cache(64), double.new_cache(64));
+    for input in [Tensor::new(vec![1, 5, 7, 9], &[4]), Tensor::new(vec![3], &[1])] {
+        let hidden = single.hidden_states(&input, &mut cache32);
+        let expected = single.logits(&hidden, &mut cache32);
+        let hidden = double.hidden_states(&input, &mut cache64);
+        let logits = double.logits(&hidden, &mut cache64);
+        let logits = Tensor::new(logits.data().iter().map(|&x| x as f32).collect(), logits.shape());
+        assert!(logits.close_to(&expected, 1e-4));
+    }
+    assert_eq!(cache64.len(), cache32.len());
+}
+
+#[test]
+pub fn test_load_safetensors() {
+    use crate::tensor::float_eq;
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("..").join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    assert_eq!(model.vocab, 2048);
+    assert_eq!(model.n_layers, 2);
+    assert_eq!(model.n_q_h, 8);
+    assert_eq!(model.n_kv_h, 4);
+    assert_eq!(model.d, 128);
+    assert_eq!(model.dqkv, 16);
+    assert_eq!(model.di, 384);
+
+    assert!(float_eq(
+        &model.params.embedding_table.data()[50],
+        &0.14453125,
+        1e-6
+    ));
+    assert_eq!(
+        model.params.lm_head.data()[10],
+        model.params.embedding_table.data()[10]
+    );
+    assert!(float_eq(
+        &model.params.rms_att_w[0].data()[10],
+        &0.18652344,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.rms_ffn_w[1].data()[10],
+        &0.32421875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.rms_out_w.data()[100],
+        &0.73046875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.w_down[0].data()[100],
+        &-0.0625,
+        1e-6
+    ));
+    assert!(float_eq(&model.params.w_up[0].data()[100], &1.46875, 1e-6));
+    assert!(float_eq(
+        &model.params.w_gate[1].data()[100],
+        &0.296875,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.wq[1].data()[100],
+        &0.032226563,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.wk[1].data()[100],
+        &-0.21386719,
+        1e-6
+    ));
+    assert!(float_eq(
+        &model.params.wv[0].data()[100],
+        &0.041015625,
+        1e-6
+    ));
+    assert!(float_eq(&model.params.wo[0].data()[100], &0.01965332, 1e-6));
+}
+
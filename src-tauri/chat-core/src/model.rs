@@ -0,0 +1,724 @@
+use std::fs::File;
+use std::vec;
+
+use crate::llama::Llama;
+use crate::generate::GenerateOptions;
+use crate::grammar::GrammarConstraint;
+use crate::kvcache::KVCache;
+use crate::operators::{self as OP, random_sample, sample_from, sample_probs};
+use crate::tensor::Tensor;
+use safetensors::SafeTensors;
+use std::path::Path;
+// One generated token with its logprob and the most likely tokens at that step
+#[derive(Clone, Debug)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub logprob: f32,
+    pub top: Vec<(u32, f32)>, // (token, logprob), most likely first
+}
+
+// How embed() pools the hidden states of a text
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    Mean,
+    LastToken,
+    PerToken,
+}
+
+// A model family: how it reads its config.json and weights, runs its decoder and what cache it
+// needs. Model builds generation, beam search, scoring and embeddings on top of these, so a new
+// family only has to implement this trait and register its model_type in ARCHITECTURES.
+pub trait ModelArchitecture: Send + Sync {
+    // Parses config.json and maps the checkpoint's tensors to the family's weights
+    fn load(config: serde_json::Value, weights: &SafeTensors) -> Self
+    where
+        Self: Sized;
+    fn vocab_size(&self) -> usize;
+    fn hidden_size(&self) -> usize;
+    fn max_seq_len(&self) -> usize;
+    fn eos_token_id(&self) -> u32;
+    // A cache that holds up to len positions
+    fn new_cache(&self, len: usize) -> KVCache<f32>;
+    // Runs input through the decoder, returning the final hidden states (seq_len, hidden_size)
+    fn hidden_states(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32>;
+    // Projects hidden states (rows, hidden_size) to logits (rows, vocab_size). Both calls get the
+    // cache so an implementation can keep its scratch buffers there (KVCache::put_workspace).
+    fn logits(&self, hidden_states: &Tensor<f32>, cache: &mut KVCache<f32>) -> Tensor<f32>;
+}
+
+type Loader = fn(serde_json::Value, &SafeTensors) -> Box<dyn ModelArchitecture>;
+
+// Implementations by config.json model_type; checkpoints of other types load as Llama
+const ARCHITECTURES: &[(&[&str], Loader)] = &[
+    (&["llama", "mistral", "mixtral", "qwen2", "qwen2_moe"], load::<Llama<f32>>),
+];
+
+fn load<A: ModelArchitecture + 'static>(config: serde_json::Value, weights: &SafeTensors) -> Box<dyn ModelArchitecture> {
+    Box::new(A::load(config, weights))
+}
+
+pub struct Model {
+    arch: Box<dyn ModelArchitecture>,
+}
+
+impl Model {
+    pub fn new(arch: impl ModelArchitecture + 'static) -> Self {
+        Model { arch: Box::new(arch) }
+    }
+
+    pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Self {
+        let config = File::open(model_dir.as_ref().join("config.json")).unwrap();
+        let config: serde_json::Value = serde_json::from_reader(config).unwrap();
+        let model_file = std::fs::read(model_dir.as_ref().join("model.safetensors")).unwrap();
+        let safetensor = SafeTensors::deserialize(&model_file).unwrap();
+        let model_type = config["model_type"].as_str().unwrap_or_default();
+        let loader = ARCHITECTURES
+            .iter()
+            .find(|(types, _)| types.contains(&model_type))
+            .map_or(load::<Llama<f32>> as Loader, |(_, loader)| *loader);
+        Model { arch: loader(config, &safetensor) }
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.arch.vocab_size()
+    }
+
+    pub fn eos_token_id(&self) -> u32 {
+        self.arch.eos_token_id()
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.arch.max_seq_len()
+    }
+
+    pub fn new_cache(&self) -> KVCache<f32> {
+        self.arch.new_cache(self.arch.max_seq_len())
+    }
+
+    // How many tokens can be generated after feeding prompt_len more tokens to `cache` before
+    // positions run past max_seq_len; the last generated token is never fed. 0 when the prompt
+    // is empty or does not fit.
+    pub fn room(&self, cache: &KVCache<f32>, prompt_len: usize) -> usize {
+        match prompt_len {
+            0 => 0,
+            n => (self.max_seq_len() + 1).saturating_sub(cache.len() + n),
+        }
+    }
+
+    pub fn forward(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        self.forward_logits(input, cache, false)
+    }
+
+    // Same as forward, but returns the logits of every input position as (seq_len, vocab):
+    // row i is the distribution of the token following input[i].
+    pub fn forward_all(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        self.forward_logits(input, cache, true)
+    }
+
+    // Runs input through the model and projects the final hidden states to logits: only the
+    // last position (1, vocab) by default, every position (seq_len, vocab) with all_positions,
+    // as scoring a prompt or verifying draft tokens needs.
+    pub fn forward_logits(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>, all_positions: bool) -> Tensor<f32> {
+        let seq_len = input.size();
+        let d = self.arch.hidden_size();
+        let hidden_states = self.arch.hidden_states(input, cache);
+        let rows = if all_positions { seq_len } else { 1 };
+        self.arch.logits(&hidden_states.slice((seq_len - rows) * d, &[rows, d]), cache)
+    }
+
+    // Hidden states of every input position after the final rms_norm, (seq_len, d)
+    pub fn hidden_states(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        self.arch.hidden_states(input, cache)
+    }
+
+    // Embeddings of a text: the final hidden states pooled per `pooling`. Mean and last-token
+    // pooling give one vector of length d, per-token pooling one vector per token.
+    // Inputs longer than max_seq_len are truncated.
+    pub fn embed(&self, token_ids: &[u32], pooling: Pooling) -> Vec<Vec<f32>> {
+        let d = self.arch.hidden_size();
+        let token_ids = &token_ids[..token_ids.len().min(self.max_seq_len())];
+        if token_ids.is_empty() {
+            return match pooling {
+                Pooling::PerToken => Vec::new(),
+                _ => vec![vec![0.; d]],
+            };
+        }
+        let seq_len = token_ids.len();
+        let mut cache = self.arch.new_cache(seq_len);
+        let input = Tensor::<u32>::new(token_ids.to_vec(), &[seq_len]);
+        let hidden_states = self.hidden_states(&input, &mut cache);
+        let mut rows = hidden_states.data().chunks(d).map(|row| row.to_vec());
+        match pooling {
+            Pooling::PerToken => rows.collect(),
+            Pooling::LastToken => vec![rows.next_back().unwrap()],
+            Pooling::Mean => {
+                let mut mean = vec![0.; d];
+                for row in rows {
+                    mean.iter_mut().zip(row).for_each(|(m, x)| *m += x / seq_len as f32);
+                }
+                vec![mean]
+            }
+        }
+    }
+
+    pub fn generate(
+        &self,
+        token_ids: &[u32],
+        max_len: usize,
+        top_p: f32,
+        top_k: u32,
+        temperature: f32,
+    ) -> Vec<u32> {
+        let mut result = Vec::<u32>::from(token_ids);
+        let mut cache = self.new_cache();
+       let mut next = random_sample(&self.forward(&Tensor::new(result.clone(), &[result.len()]), &mut cache), top_p, top_k, temperature); 
+       result.push(next);
+        //forward(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> 
+        while result.len() < max_len && next != self.eos_token_id()  {
+            let input = Tensor::new(vec![next], &[1]);    
+            let t = self.forward(&input, &mut cache);   
+          //  t.print();
+            next = random_sample(&t, top_p, top_k, temperature);
+           // println!("next: {next} {:?}",input.shape()); 
+            result.push(next); 
+          //  println!("result：{result:?}");
+        }
+        result
+    }
+
+    pub fn chat_generate(
+        &self,
+        token_ids: &[u32], 
+        cache: &mut KVCache<f32>,
+        max_len: usize,
+        top_p: f32,
+        top_k: u32,
+        temperature: f32,
+    ) -> Vec<u32> {
+        let result = Vec::<u32>::from(token_ids);
+       let mut next = random_sample(&self.forward(&Tensor::new(result.clone(), &[result.len()]), cache), top_p, top_k, temperature); 
+       let mut result = Vec::<u32>::default();
+       result.push(next);
+            while result.len() < max_len && next != self.eos_token_id()  {
+                let input = Tensor::new(vec![next], &[1]);    
+                let t = self.forward(&input, cache);   
+                next = random_sample(&t, top_p, top_k, temperature);
+                result.push(next); 
+            }
+        result
+
+    }
+
+    // chat_generate that hands every token to `on_token` as soon as it is sampled, for
+    // streaming; generation stops early when the callback returns false. Uses the sampling
+    // fields of `options`, with max_len cut to the room left in the cache.
+    pub fn chat_generate_stream(
+        &self,
+        token_ids: &[u32],
+        cache: &mut KVCache<f32>,
+        options: &GenerateOptions,
+        mut on_token: impl FnMut(u32) -> bool,
+    ) -> Vec<u32> {
+        let GenerateOptions { top_p, top_k, temperature, .. } = *options;
+        let max_len = options.max_len.min(self.room(cache, token_ids.len()));
+        if max_len == 0 {
+            return Vec::new();
+        }
+        let mut logits = self.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), cache);
+        let mut result = Vec::<u32>::new();
+        loop {
+            let next = random_sample(&logits, top_p, top_k, temperature);
+            result.push(next);
+            if !on_token(next) || result.len() >= max_len || next == self.eos_token_id() {
+                break;
+            }
+            logits = self.forward(&Tensor::new(vec![next], &[1]), cache);
+        }
+        result
+    }
+
+    // Beam search over options.beam_width hypotheses. Every live beam decodes in a cache of its
+    // own; when a beam has several children the extra ones get the cache of a beam that has none
+    // and copy only the rows generated since the prompt. Finished hypotheses are ranked by
+    // logprob / len^length_penalty; with early_stopping the search ends as soon as beam_width of
+    // them are done, otherwise once no live beam can still beat the worst kept one. Returns up
+    // to beam_width (tokens, cumulative logprob) pairs, best first, and leaves `cache` holding
+    // the best answer like chat_generate does.
+    pub fn beam_search(&self, token_ids: &[u32], cache: &mut KVCache<f32>, options: &GenerateOptions) -> Vec<(Vec<u32>, f32)> {
+        struct Beam {
+            tokens: Vec<u32>,
+            logprob: f32,
+            logits: Tensor<f32>,
+        }
+        let GenerateOptions { beam_width, length_penalty, early_stopping, .. } = *options;
+        let beam_width = beam_width.max(1);
+        let max_len = options.max_len.min(self.room(cache, token_ids.len()));
+        if max_len == 0 {
+            return Vec::new();
+        }
+        let score = |logprob: f32, len: usize| logprob / (len as f32).powf(length_penalty);
+        // Best score any continuation of a live beam can reach: appending tokens only lowers
+        // the logprob, so with a positive penalty the longest answer divides it most, otherwise
+        // the shortest
+        let bound = |logprob: f32, len: usize| match length_penalty > 0. {
+            true => score(logprob, max_len),
+            false => score(logprob, len + 1),
+        };
+
+        let logits = self.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), cache);
+        let prompt_end = cache.len();
+        let mut beams = vec![Beam { tokens: Vec::new(), logprob: 0., logits }];
+        let mut caches = vec![cache.fork()]; // caches[i] belongs to beams[i]
+        let mut finished: Vec<(Vec<u32>, f32, f32)> = Vec::new(); // (tokens, logprob, score)
+
+        while !beams.is_empty() {
+            // best `beam_width` extensions of every beam, then the best of those overall
+            let mut candidates: Vec<(usize, u32, f32)> = Vec::new();
+            for (b, beam) in beams.iter().enumerate() {
+                let log_probs = OP::log_softmax(&beam.logits);
+                for (tok, logprob) in OP::top_n(&log_probs, beam_width) {
+                    candidates.push((b, tok, beam.logprob + logprob));
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            let mut children = Vec::with_capacity(beam_width);
+            for (b, tok, logprob) in candidates {
+                if children.len() == beam_width {
+                    break;
+                }
+                let mut tokens = beams[b].tokens.clone();
+                tokens.push(tok);
+                if tok == self.eos_token_id() || tokens.len() >= max_len {
+                    let len = tokens.len();
+                    finished.push((tokens, logprob, score(logprob, len)));
+                } else {
+                    children.push((b, tokens, logprob));
+                }
+            }
+
+            // the first child of a beam inherits its cache, the others take over the caches of
+            // beams without children (or a fork while the beam count grows) and copy the rows
+            let mut inherits = vec![false; beams.len()];
+            let first: Vec<bool> = children.iter().map(|&(b, ..)| !std::mem::replace(&mut inherits[b], true)).collect();
+            let mut parents: Vec<Option<KVCache<f32>>> = caches.into_iter().map(Some).collect();
+            let mut spare: Vec<KVCache<f32>> =
+                inherits.iter().zip(&mut parents).filter(|(inherited, _)| !**inherited).filter_map(|(_, cache)| cache.take()).collect();
+            let mut child_caches: Vec<Option<KVCache<f32>>> = children.iter().map(|_| None).collect();
+            for (c, &(b, ..)) in children.iter().enumerate().filter(|&(c, _)| !first[c]) {
+                let parent = parents[b].as_ref().unwrap();
+                let mut cache = spare.pop().unwrap_or_else(|| parent.fork());
+                cache.copy_from(parent, prompt_end);
+                child_caches[c] = Some(cache);
+            }
+            for (c, &(b, ..)) in children.iter().enumerate().filter(|&(c, _)| first[c]) {
+                child_caches[c] = parents[b].take();
+            }
+
+            beams = Vec::with_capacity(children.len());
+            caches = Vec::with_capacity(children.len());
+            for ((_, tokens, logprob), cache) in children.into_iter().zip(child_caches) {
+                let mut cache = cache.unwrap();
+                let logits = self.forward(&Tensor::new(vec![*tokens.last().unwrap()], &[1]), &mut cache);
+                beams.push(Beam { tokens, logprob, logits });
+                caches.push(cache);
+            }
+
+            finished.sort_by(|a, b| b.2.total_cmp(&a.2));
+            finished.truncate(beam_width);
+            if finished.len() == beam_width {
+                let worst = finished[beam_width - 1].2;
+                let best_live = beams
+                    .iter()
+                    .map(|beam| bound(beam.logprob, beam.tokens.len()))
+                    .fold(f32::NEG_INFINITY, f32::max);
+                if early_stopping || best_live <= worst {
+                    break;
+                }
+            }
+        }
+
+        let best = &finished[0].0;
+        if best.len() > 1 {
+            let fed = &best[..best.len() - 1];
+            self.forward(&Tensor::new(fed.to_vec(), &[fed.len()]), cache);
+        }
+        finished.into_iter().map(|(tokens, logprob, _)| (tokens, logprob)).collect()
+    }
+
+    // options.n_samples independent sampled answers to the same prompt, sharing a single
+    // prefill. Each answer comes with its cumulative logprob under the model; `cache`
+    // continues with the first one.
+    pub fn sample_n(&self, token_ids: &[u32], cache: &mut KVCache<f32>, options: &GenerateOptions) -> Vec<(Vec<u32>, f32)> {
+        let n = options.n_samples.max(1);
+        let max_len = options.max_len.min(self.room(cache, token_ids.len()));
+        if max_len == 0 {
+            return Vec::new();
+        }
+        let logits = self.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), cache);
+        let mut forks: Vec<KVCache<f32>> = (1..n).map(|_| cache.fork()).collect();
+        let mut answers = Vec::with_capacity(n);
+        for i in 0..n {
+            let cache = if i == 0 { &mut *cache } else { &mut forks[i - 1] };
+            let steps = self.sample_tokens(logits.clone(), cache, max_len, options, 0);
+            answers.push((
+                steps.iter().map(|s| s.token).collect(),
+                steps.iter().map(|s| s.logprob).sum(),
+            ));
+        }
+        answers
+    }
+
+    // chat_generate that also reports, for every generated token, its logprob under the model
+    // and the options.top_logprobs most likely tokens at that step with their logprobs
+    pub fn chat_generate_logprobs(&self, token_ids: &[u32], cache: &mut KVCache<f32>, options: &GenerateOptions) -> Vec<TokenLogprob> {
+        let max_len = options.max_len.min(self.room(cache, token_ids.len()));
+        if max_len == 0 {
+            return Vec::new();
+        }
+        let logits = self.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), cache);
+        self.sample_tokens(logits, cache, max_len, options, options.top_logprobs)
+    }
+
+    // Sampling loop shared by sample_n and chat_generate_logprobs, starting from the logits
+    // of the prompt's last position; max_len must already fit the cache
+    fn sample_tokens(
+        &self,
+        mut logits: Tensor<f32>,
+        cache: &mut KVCache<f32>,
+        max_len: usize,
+        options: &GenerateOptions,
+        top_n: usize,
+    ) -> Vec<TokenLogprob> {
+        let GenerateOptions { top_p, top_k, temperature, .. } = *options;
+        let mut steps = Vec::<TokenLogprob>::new();
+        loop {
+            let next = random_sample(&logits, top_p, top_k, temperature);
+            let log_probs = OP::log_softmax(&logits);
+            steps.push(TokenLogprob {
+                token: next,
+                logprob: log_probs[next as usize],
+                top: OP::top_n(&log_probs, top_n),
+            });
+            if steps.len() >= max_len || next == self.eos_token_id() {
+                break;
+            }
+            logits = self.forward(&Tensor::new(vec![next], &[1]), cache);
+        }
+        steps
+    }
+
+    // chat_generate restricted to the tokens `constraint` allows at every step. Generation
+    // stops once only eos fits. An answer that does not complete the grammar is an error: a
+    // token the grammar rejects, a dead end, or max_len or the cache running out first.
+    pub fn constrained_generate(
+        &self,
+        token_ids: &[u32],
+        cache: &mut KVCache<f32>,
+        options: &GenerateOptions,
+        constraint: &mut GrammarConstraint,
+    ) -> Result<Vec<u32>, String> {
+        let GenerateOptions { top_p, top_k, temperature, .. } = *options;
+        let max_len = options.max_len.min(self.room(cache, token_ids.len()));
+        let mut result = Vec::<u32>::new();
+        if max_len > 0 {
+            let mut logits = self.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), cache);
+            loop {
+                constraint.apply(&mut logits);
+                let next = random_sample(&logits, top_p, top_k, temperature);
+                constraint.accept(next)?;
+                result.push(next);
+                if result.len() >= max_len || next == self.eos_token_id() {
+                    break;
+                }
+                logits = self.forward(&Tensor::new(vec![next], &[1]), cache);
+            }
+        }
+        if !constraint.is_complete() {
+            return Err(format!("answer truncated after {} tokens, before the grammar was complete", result.len()));
+        }
+        Ok(result)
+    }
+
+    // Speculative decoding: the small `draft` model proposes up to options.draft_tokens tokens,
+    // this model checks all of them in one forward_all pass and keeps the longest accepted
+    // prefix. Accepting with probability min(1, p/q) and resampling rejections from
+    // max(0, p - q) leaves the output distributed exactly as chat_generate with the same
+    // sampling options. Both caches must hold the same history and do again on return;
+    // rejected positions are dropped with reset_len.
+    pub fn speculative_generate(
+        &self,
+        draft: &Model,
+        token_ids: &[u32],
+        cache: &mut KVCache<f32>,
+        draft_cache: &mut KVCache<f32>,
+        options: &GenerateOptions,
+    ) -> Vec<u32> {
+        assert!(self.vocab_size() == draft.vocab_size(), "draft model must share the tokenizer");
+        assert!(cache.len() == draft_cache.len(), "draft cache must hold the same history");
+        let GenerateOptions { top_p, top_k, temperature, .. } = *options;
+        let n_draft = options.draft_tokens.max(1);
+        let max_len = options.max_len.min(self.room(cache, token_ids.len())).min(draft.room(draft_cache, token_ids.len()));
+        if max_len == 0 {
+            return Vec::new();
+        }
+        // verifying feeds the last drafted token as well, which must still have a position
+        let max_seq_len = self.max_seq_len().min(draft.max_seq_len());
+        let logits = self.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), cache);
+        let mut next = random_sample(&logits, top_p, top_k, temperature);
+        let mut result = vec![next];
+        // committed tokens the draft model has not been fed yet
+        let mut pending = token_ids.to_vec();
+        pending.push(next);
+
+        while result.len() < max_len && next != self.eos_token_id() {
+            // 1. draft k tokens autoregressively
+            let k = n_draft.min(max_len - result.len()).min(max_seq_len - cache.len() - 1);
+            let draft_base = draft_cache.len();
+            let n_pending = pending.len();
+            let mut drafted = Vec::<u32>::with_capacity(k);
+            let mut draft_probs = Vec::<Vec<f32>>::with_capacity(k);
+            if k > 0 {
+                let mut input = std::mem::take(&mut pending);
+                while drafted.len() < k {
+                    let t = draft.forward(&Tensor::new(input.clone(), &[input.len()]), draft_cache);
+                    let probs = sample_probs(&t, top_p, top_k, temperature);
+                    let tok = sample_from(&probs);
+                    drafted.push(tok);
+                    draft_probs.push(probs);
+                    if tok == self.eos_token_id() {
+                        break;
+                    }
+                    input = vec![tok];
+                }
+            }
+
+            // 2. verify [next, d1..dk] with a single target pass
+            let past = cache.len();
+            let mut verify = vec![next];
+            verify.extend_from_slice(&drafted);
+            let logits = self.forward_all(&Tensor::new(verify.clone(), &[verify.len()]), cache);
+            let row = |i: usize| logits.slice(i * self.vocab_size(), &[self.vocab_size()]);
+
+            // 3. accept the longest prefix, then one corrected or bonus token
+            let mut accepted = 0;
+            let mut extra = None;
+            for (i, &tok) in drafted.iter().enumerate() {
+                let p = sample_probs(&row(i), top_p, top_k, temperature);
+                let q = &draft_probs[i];
+                if rand::random::<f32>() * q[tok as usize] < p[tok as usize] {
+                    accepted += 1;
+                    result.push(tok);
+                    if tok == self.eos_token_id() || result.len() >= max_len {
+                        break;
+                    }
+                } else {
+                    let residual: Vec<f32> = p.iter().zip(q).map(|(p, q)| (p - q).max(0.)).collect();
+                    extra = Some(if residual.iter().any(|r| *r > 0.) {
+                        sample_from(&residual)
+                    } else {
+                        sample_from(&p)
+                    });
+                    break;
+                }
+            }
+            let finished = result.last() == Some(&self.eos_token_id()) || result.len() >= max_len;
+            if extra.is_none() && accepted == drafted.len() && !finished {
+                extra = Some(random_sample(&row(drafted.len()), top_p, top_k, temperature));
+            }
+            if let Some(tok) = extra {
+                result.push(tok);
+            }
+            next = *result.last().unwrap();
+
+            // 4. roll both caches back to the committed prefix, excluding `next` itself
+            cache.reset_len(past + 1 + accepted - extra.is_none() as usize);
+            if !drafted.is_empty() {
+                let draft_fed = accepted.min(drafted.len() - 1);
+                draft_cache.reset_len(draft_base + n_pending + draft_fed);
+                pending = drafted[draft_fed..accepted].to_vec();
+            }
+            pending.extend(extra);
+        }
+        // the last token is not fed to either model
+        pending.pop();
+        if !pending.is_empty() {
+            draft.forward(&Tensor::new(pending.clone(), &[pending.len()]), draft_cache);
+        }
+        result
+    }
+}
+
+// A toy family: the hidden state of a token is its one-hot vector, and its logits point at the
+// next token id (skipping one after `skip`, to disagree with a plain counter). Generation only
+// sees the trait.
+#[cfg(test)]
+pub(crate) struct Counter {
+    pub vocab: usize,
+    pub skip: Option<usize>,
+}
+
+#[cfg(test)]
+impl Counter {
+    pub fn new(vocab: usize) -> Self {
+        Counter { vocab, skip: None }
+    }
+}
+
+#[cfg(test)]
+impl ModelArchitecture for Counter {
+    fn load(config: serde_json::Value, _: &SafeTensors) -> Self {
+        Counter::new(config["vocab_size"].as_u64().unwrap() as usize)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab
+    }
+
+    fn hidden_size(&self) -> usize {
+        self.vocab
+    }
+
+    fn max_seq_len(&self) -> usize {
+        16
+    }
+
+    fn eos_token_id(&self) -> u32 {
+        0
+    }
+
+    fn new_cache(&self, len: usize) -> KVCache<f32> {
+        KVCache::new(1, len, 1, 0)
+    }
+
+    fn hidden_states(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+        assert!(cache.len() + input.size() <= self.max_seq_len(), "positions past max_seq_len");
+        cache.increment(input.size());
+        let mut data = vec![0.; input.size() * self.vocab];
+        for (i, &token) in input.data().iter().enumerate() {
+            data[i * self.vocab + token as usize] = 1.;
+        }
+        Tensor::new(data, &[input.size(), self.vocab])
+    }
+
+    fn logits(&self, hidden_states: &Tensor<f32>, _: &mut KVCache<f32>) -> Tensor<f32> {
+        let mut data = vec![0.; hidden_states.size()];
+        for (row, logits) in hidden_states.data().chunks(self.vocab).zip(data.chunks_mut(self.vocab)) {
+            let token = row.iter().position(|&x| x == 1.).unwrap();
+            let step = if Some(token) == self.skip { 2 } else { 1 };
+            logits[(token + step) % self.vocab] = 1.;
+        }
+        Tensor::new(data, hidden_states.shape())
+    }
+}
+
+#[test]
+fn test_custom_architecture() {
+    let model = Model::new(Counter::new(5));
+    assert_eq!(model.generate(&[2], 16, 1., 1, 1.), vec![2, 3, 4, 0]);
+    let mut cache = model.new_cache();
+    let logits = model.forward_all(&Tensor::new(vec![1, 2], &[2]), &mut cache);
+    assert_eq!(logits.data()[..5], [0., 0., 1., 0., 0.]);
+    assert_eq!(logits.data()[5..], [0., 0., 0., 1., 0.]);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(model.embed(&[3, 1], Pooling::Mean), vec![vec![0., 0.5, 0., 0.5, 0.]]);
+}
+
+#[test]
+fn test_generate_room() {
+    // max_seq_len is 16: a 14-token prompt leaves room for three tokens, the last one unfed
+    let model = Model::new(Counter::new(20));
+    let options = GenerateOptions { max_len: 10, top_k: 1, ..GenerateOptions::default() };
+    let prompt: Vec<u32> = (1..=14).collect();
+    let mut cache = model.new_cache();
+    assert_eq!(model.chat_generate_stream(&prompt, &mut cache, &options, |_| true), vec![15, 16, 17]);
+    assert_eq!(cache.len(), 16);
+    let mut cache = model.new_cache();
+    assert!(model.chat_generate_stream(&[1; 17], &mut cache, &options, |_| true).is_empty());
+    assert!(model.chat_generate_stream(&[], &mut cache, &options, |_| true).is_empty());
+    assert_eq!(cache.len(), 0);
+    let options = GenerateOptions { top_logprobs: 2, ..options };
+    let steps = model.chat_generate_logprobs(&prompt, &mut cache, &options);
+    assert_eq!(steps.iter().map(|step| step.token).collect::<Vec<_>>(), vec![15, 16, 17]);
+    assert!(steps.iter().all(|step| step.top.len() == 2 && step.top[0].0 == step.token));
+    assert!(model.chat_generate_logprobs(&prompt, &mut cache, &options).is_empty());
+}
+
+#[test]
+fn test_beam_search() {
+    let model = Model::new(Counter::new(5));
+    for length_penalty in [1., 0., -1., 2.] {
+        let options = GenerateOptions { beam_width: 3, length_penalty, ..GenerateOptions::default() };
+        let mut cache = model.new_cache();
+        let beams = model.beam_search(&[2], &mut cache, &options);
+        assert_eq!(beams.len(), 3);
+        let scores: Vec<f32> = beams.iter().map(|(tokens, logprob)| logprob / (tokens.len() as f32).powf(length_penalty)).collect();
+        assert!(scores.windows(2).all(|w| w[0] >= w[1]), "{scores:?}");
+        for (tokens, _) in &beams {
+            assert!(tokens.last() == Some(&0) || tokens.len() == 16, "{tokens:?}");
+        }
+        // `cache` continues from the best answer, its last token not fed yet
+        assert_eq!(cache.len(), beams[0].0.len());
+        // logprob per token is highest counting up to eos; without a penalty the unlikely but
+        // immediate eos wins, while a strong one favours the longest answers
+        match length_penalty {
+            1. => assert_eq!(beams[0].0, vec![3, 4, 0]),
+            2. => assert_eq!(beams[0].0.len(), 16),
+            _ => assert_eq!(beams[0].0, vec![0]),
+        }
+    }
+}
+
+#[test]
+fn test_constrained_generate() {
+    use crate::grammar::{Grammar, TokenTrie};
+    // token i is the letter 'a' + i, eos (0) has no text
+    let model = Model::new(Counter::new(5));
+    let trie = TokenTrie::new((0..5u8).map(|i| if i == 0 { Vec::new() } else { vec![b'a' + i] }).collect());
+    let grammar = Grammar::parse(r#"root ::= "b" [b-e]"#).unwrap();
+    let options = GenerateOptions { top_k: 1, ..GenerateOptions::default() };
+    let mut cache = model.new_cache();
+    let mut constraint = GrammarConstraint::new(&grammar, &trie, 0);
+    assert_eq!(model.constrained_generate(&[3], &mut cache, &options, &mut constraint), Ok(vec![1, 2, 0]));
+    // cut off by max_len, or by the cache, before the second letter
+    let truncated = Err("answer truncated after 1 tokens, before the grammar was complete".to_string());
+    let short = GenerateOptions { max_len: 1, ..options.clone() };
+    let mut constraint = GrammarConstraint::new(&grammar, &trie, 0);
+    assert_eq!(model.constrained_generate(&[3], &mut model.new_cache(), &short, &mut constraint), truncated);
+    let mut constraint = GrammarConstraint::new(&grammar, &trie, 0);
+    assert_eq!(model.constrained_generate(&[1; 16], &mut model.new_cache(), &options, &mut constraint), truncated);
+    // a complete sentence needs no room for eos
+    let exact = GenerateOptions { max_len: 2, ..options };
+    let mut constraint = GrammarConstraint::new(&grammar, &trie, 0);
+    assert_eq!(model.constrained_generate(&[3], &mut model.new_cache(), &exact, &mut constraint), Ok(vec![1, 2]));
+}
+
+#[test]
+fn test_speculative_generate() {
+    // the draft counts like the model except after 5, so drafts through 5 are rejected there
+    let model = Model::new(Counter::new(12));
+    let draft = Model::new(Counter { skip: Some(5), ..Counter::new(12) });
+    let greedy = GenerateOptions { top_k: 1, ..GenerateOptions::default() };
+    let expected = model.chat_generate(&[1], &mut model.new_cache(), 500, 1., 1, 1.);
+    assert_eq!(expected, vec![2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 0]);
+    for draft_tokens in 1..=6 {
+        let options = GenerateOptions { draft_tokens, ..greedy.clone() };
+        let (mut cache, mut draft_cache) = (model.new_cache(), draft.new_cache());
+        let out = model.speculative_generate(&draft, &[1], &mut cache, &mut draft_cache, &options);
+        assert_eq!(out, expected, "{draft_tokens} draft tokens");
+        // both caches hold the prompt and every answer token but the last
+        assert_eq!((cache.len(), draft_cache.len()), (out.len(), out.len()));
+    }
+    // cut by max_len, and by the positions left: a 13-token prompt leaves room for 4 tokens
+    let options = GenerateOptions { max_len: 3, draft_tokens: 4, ..greedy.clone() };
+    let (mut cache, mut draft_cache) = (model.new_cache(), draft.new_cache());
+    assert_eq!(model.speculative_generate(&draft, &[1], &mut cache, &mut draft_cache, &options), vec![2, 3, 4]);
+    assert_eq!((cache.len(), draft_cache.len()), (3, 3));
+    let (model, draft) = (Model::new(Counter::new(20)), Model::new(Counter::new(20)));
+    let prompt: Vec<u32> = (1..=13).collect();
+    let options = GenerateOptions { draft_tokens: 4, ..greedy };
+    let (mut cache, mut draft_cache) = (model.new_cache(), draft.new_cache());
+    assert_eq!(model.speculative_generate(&draft, &prompt, &mut cache, &mut draft_cache, &options), vec![14, 15, 16, 17]);
+    assert_eq!((cache.len(), draft_cache.len()), (16, 16));
+}
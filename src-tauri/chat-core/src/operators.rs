@@ -0,0 +1,555 @@
+use rayon::prelude::*;
+
+use crate::dtype::Float;
+use crate::rope::RopeTable;
+use crate::tensor::{Tensor, MAX_DIMS};
+
+// get (row) vectors from a 2D table given a list of indices
+pub fn gather<T: Copy + Default>(y: &mut Tensor<T>, indices: &Tensor<u32>, table: &Tensor<T>) {
+    let length = indices.size();
+    let table_shape = table.shape();
+    assert!(table_shape.len() == 2);
+    let dim = table_shape[1];
+    assert!(y.size() == length * dim);
+    for i in 0..length {
+        let src = &table.data()[indices.data()[i] as usize * dim..][..dim];
+        let dst = &mut y.data_mut()[i * dim..][..dim];
+        dst.copy_from_slice(src);
+    }
+}
+
+// RoPE: Rotary Positional Embedding, with cos/sin looked up in the model's table
+pub fn rope<T: Float>(y: &mut Tensor<T>, start_pos: usize, table: &RopeTable) {
+    let shape = y.shape();
+    assert!(shape.len() == 3);
+    let seq_len = shape[0];
+    let n_heads = shape[1];
+    let d = shape[2];
+    assert!(d == table.head_dim());
+    let data = y.data_mut();
+    for tok in 0..seq_len {
+        let (cos, sin) = table.at(start_pos + tok);
+        for head in 0..n_heads {
+            let base = tok * n_heads * d + head * d;
+            for i in 0..d / 2 {
+                let (cos, sin) = (T::from_f32(cos[i]), T::from_f32(sin[i]));
+                let a = data[base + i];
+                let b = data[base + i + d / 2];
+                data[base + i] = a * cos - b * sin;
+                data[base + i + d / 2] = b * cos + a * sin;
+            }
+        }
+    }
+}
+
+// softmax over the last dimension; -inf entries (masked out) get probability 0
+pub fn softmax<T: Float>(y: &mut Tensor<T>) {
+    let dim = y.shape()[y.shape().len() - 1];
+    let data = y.data_mut();
+    for row in data.chunks_mut(dim) {
+        let max = row.iter().fold(T::NEG_INFINITY, |a, b| a.max(*b));
+        let mut sum = T::ZERO;
+        for x in row.iter_mut() {
+            *x = (*x - max).exp();
+            sum += *x;
+        }
+        row.iter_mut().for_each(|x| *x /= sum);
+    }
+}
+
+pub fn rms_norm<T: Float>(y: &mut Tensor<T>, x: &Tensor<T>, w: &Tensor<T>, epsilon: T) {
+    let ndim = y.shape().len();
+    assert!(ndim >= 2);
+    let seq_len = y.shape()[ndim - 2];
+    let total_seq_len = y.shape()[ndim - 1];
+    let _y = y.data_mut();
+    let _x = x.data();
+    let _w = w.data();
+    // todo!("实现 rms_norm，计算前做一些必要的检查会帮助你后续调试")
+    for i in 0..seq_len {
+        let mut sum = T::ZERO;
+        for &x in &_x[i * total_seq_len..(i + 1) * total_seq_len] {
+            sum += x * x;
+        }
+        //let tmp = 1.0 / total_seq_len as f32;
+        sum = sum / T::from_f32(total_seq_len as f32) + epsilon;
+        sum = sum.powf(T::from_f32(0.5));
+        let idx_t = i * total_seq_len;
+        for j in 0..total_seq_len {
+            _y[idx_t + j] = _x[idx_t + j] * _w[j] / sum;
+        }
+    }
+}
+
+// y = sigmoid(x) * x * y
+// hint: this is an element-wise operation
+pub fn silu<T: Float>(y: &mut Tensor<T>, x: &Tensor<T>) {
+    let len = y.size();
+    assert!(len == x.size());
+
+    let _y = y.data_mut();
+    let _x = x.data();
+    for i in 0..len {
+        _y[i] = _x[i] * _y[i] / (T::ONE + T::from_f32(std::f32::consts::E).powf(-_x[i]));
+    }
+
+    // todo!("实现 silu，这里给了一些前期准备工作的提示，你可以参考")
+}
+
+// C = beta * C + alpha * A @ B^T over the last two dimensions; leading dimensions are batch
+// dimensions, with A and B broadcast to C's (a 2D weight is shared by every batch). A and B
+// may be strided views, such as attention heads permuted out of a (seq, n_heads, d) tensor.
+// hint: You don't need to do an explicit transpose of B
+pub fn matmul_transb<T: Float>(c: &mut Tensor<T>, beta: T, a: &Tensor<T>, b: &Tensor<T>, alpha: T) {
+    let ndim = c.shape().len();
+    assert!(ndim >= 2);
+    let seq_len = c.shape()[ndim - 2];
+    let mid = a.shape()[a.shape().len() - 1];
+    let total_seq_len = c.shape()[ndim - 1];
+    assert!(b.shape()[b.shape().len() - 1] == mid, "matmul_transb of {:?} and {:?}", a.shape(), b.shape());
+    let batch_shape = &c.shape()[..ndim - 2];
+    let a = batched_rows(a, batch_shape, seq_len);
+    let b = batched_rows(b, batch_shape, total_seq_len);
+    let (row_a, row_b) = (a.strides()[ndim - 2], b.strides()[ndim - 2]);
+    let (_a, _b) = (a.storage(), b.storage());
+    let data = c.data_mut();
+    for (bc, (base_a, base_b)) in batch_starts(&a, ndim - 2).zip(batch_starts(&b, ndim - 2)).enumerate() {
+        let base_c = bc * seq_len * total_seq_len;
+        for i in 0..seq_len {
+            let x = &_a[base_a + i * row_a..][..mid];
+            for j in 0..total_seq_len {
+                let y = &_b[base_b + j * row_b..][..mid];
+                let mut sum = T::ZERO;
+                for k in 0..mid {
+                    sum += x[k] * y[k];
+                }
+                // beta == 0 ignores what C held, as BLAS does, so scratch buffers need no clearing
+                let out = &mut data[base_c + i * total_seq_len + j];
+                *out = if beta == T::ZERO { alpha * sum } else { *out * beta + alpha * sum };
+            }
+        }
+    }
+}
+
+// x broadcast to (batch_shape.., rows, cols), copied if its rows are not contiguous
+fn batched_rows<T: Float>(x: &Tensor<T>, batch_shape: &[usize], rows: usize) -> Tensor<T> {
+    let cols = x.shape()[x.shape().len() - 1];
+    let x = if cols == 1 || x.strides()[x.strides().len() - 1] == 1 { x.clone() } else { x.contiguous() };
+    let n = batch_shape.len();
+    let mut shape = [0; MAX_DIMS];
+    shape[..n].copy_from_slice(batch_shape);
+    shape[n..n + 2].copy_from_slice(&[rows, cols]);
+    x.broadcast_to(&shape[..n + 2])
+}
+
+// Buffer position of the first element of every matrix of a batched tensor
+fn batch_starts<T: Float>(x: &Tensor<T>, batch_dims: usize) -> impl Iterator<Item = usize> {
+    x.narrow(batch_dims, 0, 1).narrow(batch_dims + 1, 0, 1).positions()
+}
+
+// y += x
+pub fn add<T: Float>(y: &mut Tensor<T>, x: &Tensor<T>) {
+    assert!(y.size() == x.size(), "add of {:?} to {:?}", x.shape(), y.shape());
+    let _y = y.data_mut();
+    for (y, x) in _y.iter_mut().zip(x.iter()) {
+        *y += x;
+    }
+}
+
+// y[i] += bias for every row i of y
+pub fn add_bias<T: Float>(y: &mut Tensor<T>, bias: &Tensor<T>) {
+    let dim = bias.size();
+    assert!(y.size().is_multiple_of(dim));
+    let _y = y.data_mut();
+    for row in _y.chunks_mut(dim) {
+        row.iter_mut().zip(bias.data()).for_each(|(y, b)| *y += *b);
+    }
+}
+
+// Dot product of two tensors (treated as vectors)
+#[allow(unused)]
+pub fn dot<T: Float>(x: &Tensor<T>, y: &Tensor<T>) -> T {
+    let len = x.size();
+    assert!(len == y.size());
+    let x_ = x.data();
+    let y_ = y.data();
+    let mut sum = T::ZERO;
+    for i in 0..len {
+        sum += x_[i] * y_[i];
+    }
+    sum
+}
+
+// Keys scored together by attention before their values are summed
+const ATTENTION_TILE: usize = 64;
+
+// Fused scaled dot-product attention, out = softmax(scale * q k^T) v for every head, with the
+// k/v heads shared by n_q_h / n_kv_h query heads each. Keys are visited in tiles with an online
+// softmax: the running maximum and sum are corrected as every tile arrives, so no score matrix
+// is kept and each (query, head) pair is computed in one pass, in parallel with the others.
+// allows(i, row) tells whether query i may attend to key row; a fully masked query gives zeros.
+pub fn attention<T: Float>(
+    out: &mut Tensor<T>, // (seq, n_q_h * dqkv)
+    q: &Tensor<T>,       // (seq, n_q_h * dqkv)
+    k: &Tensor<T>,       // (total_seq, n_kv_h * dqkv)
+    v: &Tensor<T>,       // (total_seq, n_kv_h * dqkv)
+    n_kv_h: usize,
+    scale: T,
+    allows: impl Fn(usize, usize) -> bool + Sync,
+) {
+    assert!(k.shape() == v.shape() && out.shape() == q.shape(), "attention of {:?} over {:?}", q.shape(), k.shape());
+    let (kv_dim, total_seq_len) = (k.shape()[1], k.shape()[0]);
+    let dqkv = kv_dim / n_kv_h;
+    let n_q_h = q.shape()[1] / dqkv;
+    let n_groups = n_q_h / n_kv_h;
+    // Heads are addressed by flat offsets into the row-major buffers rather than through views:
+    // data() asserts q, k and v are contiguous, and plain slices keep the per-key inner loop free
+    // of view bookkeeping so dot_slices and the value accumulation vectorize.
+    let (_q, _k, _v) = (q.data(), k.data(), v.data());
+    out.data_mut().par_chunks_mut(dqkv).enumerate().for_each(|(i, acc)| {
+        let (seq, head) = (i / n_q_h, i % n_q_h);
+        let query = &_q[i * dqkv..][..dqkv];
+        let kv_offset = head / n_groups * dqkv;
+        let (mut max, mut sum) = (T::NEG_INFINITY, T::ZERO);
+        acc.fill(T::ZERO);
+        let mut scores = [T::ZERO; ATTENTION_TILE];
+        for start in (0..total_seq_len).step_by(ATTENTION_TILE) {
+            let n = ATTENTION_TILE.min(total_seq_len - start);
+            let mut tile_max = T::NEG_INFINITY;
+            for (j, score) in scores[..n].iter_mut().enumerate() {
+                let row = start + j;
+                *score = if allows(seq, row) {
+                    scale * dot_slices(query, &_k[row * kv_dim + kv_offset..][..dqkv])
+                } else {
+                    T::NEG_INFINITY
+                };
+                tile_max = tile_max.max(*score);
+            }
+            if tile_max == T::NEG_INFINITY {
+                continue;
+            }
+            // rescale what was summed under the old maximum
+            if tile_max > max {
+                let correction = (max - tile_max).exp();
+                sum *= correction;
+                acc.iter_mut().for_each(|a| *a *= correction);
+                max = tile_max;
+            }
+            for (j, &score) in scores[..n].iter().enumerate() {
+                if score == T::NEG_INFINITY {
+                    continue;
+                }
+                let p = (score - max).exp();
+                sum += p;
+                let value = &_v[(start + j) * kv_dim + kv_offset..][..dqkv];
+                acc.iter_mut().zip(value).for_each(|(a, &v)| *a += p * v);
+            }
+        }
+        if sum > T::ZERO {
+            acc.iter_mut().for_each(|a| *a /= sum);
+        }
+    });
+}
+
+// Dot product with independent partial sums, so the compiler can keep them in vector lanes
+fn dot_slices<T: Float>(x: &[T], y: &[T]) -> T {
+    const LANES: usize = 8;
+    let mut lanes = [T::ZERO; LANES];
+    let (x_chunks, y_chunks) = (x.chunks_exact(LANES), y.chunks_exact(LANES));
+    let (x_rest, y_rest) = (x_chunks.remainder(), y_chunks.remainder());
+    for (x, y) in x_chunks.zip(y_chunks) {
+        for l in 0..LANES {
+            lanes[l] += x[l] * y[l];
+        }
+    }
+    let rest: T = x_rest.iter().zip(y_rest).map(|(&x, &y)| x * y).sum();
+    lanes.into_iter().sum::<T>() + rest
+}
+
+// Sample a index from a tensor (treated as a probability vector)
+pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32) -> u32 {
+    assert!(x.shape()[x.shape().len() - 1] == x.size());
+    if temperature <= 0. || top_k < 2 || top_p <= 0. {
+        return x
+            .data()
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .unwrap()
+            .0 as _;
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    struct Probability {
+        val: f32,
+        tok: u32,
+    }
+    impl Eq for Probability {}
+    impl PartialOrd for Probability {
+        #[inline]
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Probability {
+        #[inline]
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            match self.val.total_cmp(&other.val) {
+                std::cmp::Ordering::Equal => self.tok.cmp(&other.tok),
+                ord => ord.reverse(),
+            }
+        }
+    }
+    impl From<(usize, &f32)> for Probability {
+        #[inline]
+        fn from((i, p): (usize, &f32)) -> Self {
+            Self {
+                val: *p,
+                tok: i as _,
+            }
+        }
+    }
+
+    // sort
+    let mut logits: Vec<Probability> = x
+        .data()
+        .iter()
+        .enumerate()
+        .map(Probability::from)
+        .collect::<Vec<_>>();
+    logits.sort_unstable();
+    let max = core::mem::replace(&mut logits[0].val, 1.);
+    // softmax & sum
+    for i in 1..logits.len() {
+        logits[i].val = logits[i - 1].val + ((logits[i].val - max) / temperature).exp();
+    }
+    // topk & topp & random
+    let pk = logits[(top_k as usize).min(logits.len()) - 1].val;
+    let pp = logits[logits.len() - 1].val * top_p;
+    let plimit = rand::random::<f32>() * f32::min(pk, pp);
+    // sample
+    logits.iter().find(|p| p.val >= plimit).unwrap().tok
+}
+
+// The exact distribution random_sample draws from with the same top_p, top_k and
+// temperature, as one probability per token (zero outside the kept candidates).
+pub fn sample_probs(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32) -> Vec<f32> {
+    assert!(x.shape()[x.shape().len() - 1] == x.size());
+    let mut probs = vec![0.0; x.size()];
+    if temperature <= 0. || top_k < 2 || top_p <= 0. {
+        probs[random_sample(x, top_p, top_k, temperature) as usize] = 1.0;
+        return probs;
+    }
+
+    let mut order: Vec<usize> = (0..x.size()).collect();
+    let data = x.data();
+    order.sort_unstable_by(|&a, &b| match data[b].total_cmp(&data[a]) {
+        std::cmp::Ordering::Equal => a.cmp(&b),
+        ord => ord,
+    });
+    let max = data[order[0]];
+    // same cumulative sums as random_sample
+    let mut cum = Vec::with_capacity(order.len());
+    let mut sum = 0.0;
+    for &i in order.iter() {
+        sum += ((data[i] - max) / temperature).exp();
+        cum.push(sum);
+    }
+    let pk = cum[(top_k as usize).min(cum.len()) - 1];
+    let pp = cum[cum.len() - 1] * top_p;
+    let limit = f32::min(pk, pp);
+    let mut prev = 0.0;
+    for (rank, &i) in order.iter().enumerate() {
+        if prev >= limit {
+            break;
+        }
+        probs[i] = (cum[rank].min(limit) - prev) / limit;
+        prev = cum[rank];
+    }
+    probs
+}
+
+// log(softmax(x)) of a logits vector, used to score generated tokens
+pub fn log_softmax(x: &Tensor<f32>) -> Vec<f32> {
+    let data = x.data();
+    let max = data.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    let log_sum = data.iter().map(|v| (v - max).exp()).sum::<f32>().ln();
+    data.iter().map(|v| v - max - log_sum).collect()
+}
+
+// The n largest values as (index, value) pairs, largest first
+pub fn top_n(x: &[f32], n: usize) -> Vec<(u32, f32)> {
+    let n = n.min(x.len());
+    if n == 0 {
+        return Vec::new();
+    }
+    let mut order: Vec<usize> = (0..x.len()).collect();
+    order.select_nth_unstable_by(n - 1, |&a, &b| x[b].total_cmp(&x[a]));
+    order.truncate(n);
+    order.sort_by(|&a, &b| x[b].total_cmp(&x[a]));
+    order.into_iter().map(|i| (i as u32, x[i])).collect()
+}
+
+// Draw an index from a (not necessarily normalized) probability vector
+pub fn sample_from(probs: &[f32]) -> u32 {
+    let total: f32 = probs.iter().sum();
+    let plimit = rand::random::<f32>() * total;
+    let mut acc = 0.0;
+    for (i, p) in probs.iter().enumerate() {
+        acc += p;
+        if *p > 0. && acc >= plimit {
+            return i as _;
+        }
+    }
+    // rounding left plimit just above the last partial sum
+    probs.iter().rposition(|p| *p > 0.).unwrap() as _
+}
+
+// Your implementation should at least pass the following tests:
+#[test]
+fn test_silu() {
+    let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &[1, 3]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3.], &[1, 3]);
+    silu(&mut y, &x);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(vec![1.4621172, 5.2847824, 11.43089], &[1, 3]),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_rms_norm() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let w = Tensor::<f32>::new(vec![1., 2.], &[2]);
+    rms_norm(&mut y, &x, &w, 1e-6);
+    assert!(y.close_to(
+        &Tensor::<f32>::new(
+            vec![0.6324554, 2.5298216, 0.8485281, 2.2627416],
+            &[2, 2]
+        ),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_matmul_transb() {
+    let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let b = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+    matmul_transb(&mut c, 1., &a, &b, 1.);
+    assert!(c.close_to(
+        &Tensor::<f32>::new(vec![15., 34., 35., 81.], &[2, 2]),
+        1e-3
+    ));
+}
+
+#[test]
+fn test_softmax() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., f32::NEG_INFINITY, 0., 0., 0.], &[2, 3]);
+    softmax(&mut y);
+    let e = std::f32::consts::E;
+    let third = 1. / 3.;
+    assert!(y.close_to(&Tensor::<f32>::new(vec![1. / (1. + e), e / (1. + e), 0., third, third, third], &[2, 3]), 1e-5));
+}
+
+#[test]
+fn test_attention() {
+    // the fused kernel against scores, mask, softmax and weighted sum done step by step, over
+    // several key tiles, 4 query heads sharing 2 k/v heads and a sliding window of 50 keys
+    let (seq_len, total_seq_len, n_kv_h, n_q_h, dqkv) = (3, 150, 2, 4, 5);
+    let values = |n: usize, seed: usize| (0..n).map(|i| ((i * 31 + seed) % 17) as f32 / 17. - 0.5).collect::<Vec<_>>();
+    let q = Tensor::<f32>::new(values(seq_len * n_q_h * dqkv, 1), &[seq_len, n_q_h * dqkv]);
+    let k = Tensor::<f32>::new(values(total_seq_len * n_kv_h * dqkv, 2), &[total_seq_len, n_kv_h * dqkv]);
+    let v = Tensor::<f32>::new(values(total_seq_len * n_kv_h * dqkv, 3), &[total_seq_len, n_kv_h * dqkv]);
+    let allows = |i: usize, row: usize| {
+        let query = total_seq_len - seq_len + i;
+        row <= query && query - row < 50
+    };
+    let scale = 0.5;
+    let mut out = Tensor::<f32>::default(&[seq_len, n_q_h * dqkv]);
+    attention(&mut out, &q, &k, &v, n_kv_h, scale, allows);
+
+    let (_q, _k, _v) = (q.data(), k.data(), v.data());
+    let mut expected = vec![0.; seq_len * n_q_h * dqkv];
+    for i in 0..seq_len {
+        for h in 0..n_q_h {
+            let kv = h / (n_q_h / n_kv_h) * dqkv;
+            let query = &_q[(i * n_q_h + h) * dqkv..][..dqkv];
+            let mut scores = Tensor::<f32>::new(
+                (0..total_seq_len)
+                    .map(|row| match allows(i, row) {
+                        true => scale * query.iter().zip(&_k[row * n_kv_h * dqkv + kv..][..dqkv]).map(|(a, b)| a * b).sum::<f32>(),
+                        false => f32::NEG_INFINITY,
+                    })
+                    .collect(),
+                &[1, total_seq_len],
+            );
+            softmax(&mut scores);
+            for (row, &p) in scores.data().iter().enumerate() {
+                for d in 0..dqkv {
+                    expected[(i * n_q_h + h) * dqkv + d] += p * _v[row * n_kv_h * dqkv + kv + d];
+                }
+            }
+        }
+    }
+    assert!(out.close_to(&Tensor::<f32>::new(expected, &[seq_len, n_q_h * dqkv]), 1e-4));
+
+    // a query that may see nothing gets zeros rather than NaN
+    attention(&mut out, &q, &k, &v, n_kv_h, scale, |_, _| false);
+    assert!(out.data().iter().all(|&x| x == 0.));
+}
+
+#[test]
+fn test_add_bias() {
+    let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let bias = Tensor::<f32>::new(vec![0.5, -1., 0.], &[3]);
+    add_bias(&mut y, &bias);
+    assert!(y.close_to(&Tensor::<f32>::new(vec![1.5, 1., 3., 4.5, 4., 6.], &[2, 3]), 1e-6));
+}
+
+#[test]
+fn test_sample_probs() {
+    use crate::tensor::float_eq;
+    let x = Tensor::<f32>::new(vec![1., 3., 2., 0.], &[4]);
+    let greedy = sample_probs(&x, 0.9, 1, 1.);
+    assert_eq!(greedy, vec![0., 1., 0., 0.]);
+
+    let probs = sample_probs(&x, 1., 2, 1.);
+    assert!(float_eq(&probs.iter().sum::<f32>(), &1., 1e-5));
+    assert!(float_eq(&probs[1], &(1. / (1. + (-1f32).exp())), 1e-5));
+    assert_eq!(probs[0], 0.);
+    assert_eq!(probs[3], 0.);
+    for _ in 0..16 {
+        let tok = sample_from(&probs);
+        assert!(tok == 1 || tok == 2);
+    }
+}
+
+#[test]
+fn test_log_softmax() {
+    use crate::tensor::float_eq;
+    let x = Tensor::<f32>::new(vec![1., 2., 3.], &[1, 3]);
+    let y = log_softmax(&x);
+    let sum = y.iter().map(|v| v.exp()).sum::<f32>();
+    assert!(float_eq(&sum, &1., 1e-5));
+    assert!(float_eq(&y[2], &-0.40760596, 1e-5));
+}
+
+#[test]
+fn test_top_n() {
+    let x = [0.1, 0.7, -1., 0.4];
+    assert_eq!(top_n(&x, 2), vec![(1, 0.7), (3, 0.4)]);
+    assert_eq!(top_n(&x, 10).len(), 4);
+    assert!(top_n(&x, 0).is_empty());
+}
+
+#[test]
+fn test_matmul_transb_views() {
+    // batched over a broadcast 2D operand, with B given as a transposed view
+    let a = Tensor::<f64>::new(vec![1., 2., 3., 4., 5., 6., 7., 8.], &[2, 2, 2]);
+    let b_t = Tensor::<f64>::new(vec![1., 3., 5., 2., 4., 6.], &[2, 3]).transpose(0, 1); // (3, 2)
+    let mut c = Tensor::<f64>::default(&[2, 2, 3]);
+    matmul_transb(&mut c, 0., &a, &b_t, 1.);
+    assert_eq!(c.data(), [5., 11., 17., 11., 25., 39., 17., 39., 61., 23., 53., 83.]);
+}
@@ -0,0 +1,147 @@
+use crate::config::{Architecture, LlamaConfigJson};
+use crate::dtype::Float;
+use crate::tensor::Tensor;
+use safetensors::SafeTensors;
+pub struct LLamaParams<T> {
+    // token_id to embedding lookup table
+    pub embedding_table: Tensor<T>, // (vocab_size, dim)
+    // decoder layer
+    pub rms_att_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
+    pub wq: Vec<Tensor<T>>,        // (n_heads * head_size, hidden_size) x layers
+    pub wk: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wv: Vec<Tensor<T>>,        // (n_kv_heads * head_size, hidden_size) x layers
+    pub wo: Vec<Tensor<T>>,        // (hidden_size, n_heads * head_size) x layers
+    // projection biases, only for models that have them (Qwen2)
+    pub bq: Vec<Option<Tensor<T>>>, // (n_heads * head_size, ) x layers
+    pub bk: Vec<Option<Tensor<T>>>, // (n_kv_heads * head_size, ) x layers
+    pub bv: Vec<Option<Tensor<T>>>, // (n_kv_heads * head_size, ) x layers
+    // ffn layer
+    pub rms_ffn_w: Vec<Tensor<T>>, // (hidden_size, ) x layers
+    pub w_up: Vec<Tensor<T>>,      // (intermediate_size, hidden_size) x layers
+    pub w_gate: Vec<Tensor<T>>,    // (intermediate_size, hidden_size) x layers
+    pub w_down: Vec<Tensor<T>>,    // (hidden_size, intermediate_size) x layers
+    // mixture-of-experts MLP, replacing w_up/w_gate/w_down in sparse layers
+    pub moe: Vec<Option<MoeParams<T>>>,
+    // output
+    pub rms_out_w: Tensor<T>, // (hidden_size, )
+    pub lm_head: Tensor<T>,   // (vocab_size, dim)
+}
+
+// One SwiGLU feed-forward block: w_down(silu(w_gate x) * w_up x)
+pub struct Expert<T> {
+    pub w_up: Tensor<T>,   // (expert_intermediate_size, hidden_size)
+    pub w_gate: Tensor<T>, // (expert_intermediate_size, hidden_size)
+    pub w_down: Tensor<T>, // (hidden_size, expert_intermediate_size)
+}
+
+// Sparse MLP of one layer: the router picks the top experts for every token
+pub struct MoeParams<T> {
+    pub router: Tensor<T>, // (n_experts, hidden_size)
+    pub experts: Vec<Expert<T>>,
+    // Qwen-MoE: an expert every token goes through, scaled by sigmoid(shared_expert_gate x)
+    pub shared_expert: Option<Expert<T>>,
+    pub shared_expert_gate: Option<Tensor<T>>, // (1, hidden_size)
+}
+
+impl<T: Float> LLamaParams<T> {
+    pub fn from_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
+    
+        let get_tensor = |name: &str| {
+           // println!("name: {name}");
+            match safetensor.tensor(name) {
+                Ok(data) => {
+                    // the buffer need not be aligned for f32, so decode each value from its bytes
+                    let values = data.data().chunks_exact(4).map(|b| T::from_f32(f32::from_le_bytes([b[0], b[1], b[2], b[3]])));
+                    Tensor::new(values.collect(), data.shape())
+                },
+                Err(_) => {
+                    Tensor::default(&Vec::new())
+                }
+            }
+        };
+        // biases are looked up only when the architecture uses them
+        let get_bias = |name: &str| {
+            if config.qkv_bias() {
+                assert!(safetensor.tensor(name).is_ok(), "missing bias tensor {name}");
+                Some(get_tensor(name))
+            } else {
+                None
+            }
+        };
+        // Mixtral: block_sparse_moe.experts.{e}.w1/w3/w2 (gate/up/down);
+        // Qwen-MoE: mlp.experts.{e}.gate_proj/up_proj/down_proj plus mlp.shared_expert
+        let get_moe = |layer: usize| {
+            if !config.is_sparse_layer(layer) {
+                return None;
+            }
+            let mixtral = config.architecture() == Architecture::Mixtral;
+            let prefix = if mixtral { "block_sparse_moe" } else { "mlp" };
+            let prefix = format!("model.layers.{layer}.{prefix}");
+            let get_expert = |name: &str| {
+                let [gate, up, down] = if mixtral { ["w1", "w3", "w2"] } else { ["gate_proj", "up_proj", "down_proj"] };
+                assert!(safetensor.tensor(&format!("{name}.{gate}.weight")).is_ok(), "missing expert tensor {name}.{gate}.weight");
+                Expert {
+                    w_up: get_tensor(&format!("{name}.{up}.weight")),
+                    w_gate: get_tensor(&format!("{name}.{gate}.weight")),
+                    w_down: get_tensor(&format!("{name}.{down}.weight")),
+                }
+            };
+            let has_shared = safetensor.tensor(&format!("{prefix}.shared_expert_gate.weight")).is_ok();
+            Some(MoeParams {
+                router: get_tensor(&format!("{prefix}.gate.weight")),
+                experts: (0..config.n_experts()).map(|e| get_expert(&format!("{prefix}.experts.{e}"))).collect(),
+                shared_expert: has_shared.then(|| get_expert(&format!("{prefix}.shared_expert"))),
+                shared_expert_gate: has_shared.then(|| get_tensor(&format!("{prefix}.shared_expert_gate.weight"))),
+            })
+        };
+       // get_tensor("lm_head.weight").print();
+        LLamaParams {
+            embedding_table: {
+                if config.tie_word_embeddings {
+                    get_tensor("lm_head.weight")
+                } else {
+                    get_tensor("model.embed_tokens.weight")
+                }
+            },
+            rms_att_w: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.input_layernorm.weight")))
+                .collect(),
+            wq: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.q_proj.weight")))
+                .collect(),
+            wk: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.k_proj.weight")))
+                .collect(),
+            wv: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.v_proj.weight")))
+                .collect(),
+            wo: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.self_attn.o_proj.weight")))
+                .collect(),
+            bq: (0..config.num_hidden_layers)
+                .map(|i| get_bias(&format!("model.layers.{i}.self_attn.q_proj.bias")))
+                .collect(),
+            bk: (0..config.num_hidden_layers)
+                .map(|i| get_bias(&format!("model.layers.{i}.self_attn.k_proj.bias")))
+                .collect(),
+            bv: (0..config.num_hidden_layers)
+                .map(|i| get_bias(&format!("model.layers.{i}.self_attn.v_proj.bias")))
+                .collect(),
+            rms_ffn_w: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.post_attention_layernorm.weight")))
+                .collect(),
+            w_up: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.mlp.up_proj.weight")))
+                .collect(),
+            w_gate: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.mlp.gate_proj.weight")))
+                .collect(),
+            w_down: (0..config.num_hidden_layers)
+                .map(|i| get_tensor(&format!("model.layers.{i}.mlp.down_proj.weight")))
+                .collect(),
+            moe: (0..config.num_hidden_layers).map(get_moe).collect(),
+            rms_out_w: get_tensor("model.norm.weight"),
+            lm_head: get_tensor("lm_head.weight"),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Rotary position embedding tables. The frequencies follow config.json's rope_theta and
+// rope_scaling (linear, dynamic NTK, YaRN or Llama-3); cos/sin are computed once per model for
+// every position up to max_position_embeddings, so a forward pass only looks them up.
+use crate::config::{LlamaConfigJson, RopeScaling, RopeType};
+use std::f32::consts::PI;
+
+pub struct RopeTable {
+    half: usize,   // rotated pairs per head, head_dim / 2
+    cos: Vec<f32>, // (positions, half)
+    sin: Vec<f32>, // (positions, half)
+}
+
+// Inverse frequency of every rotated pair: theta^(-2i/d)
+fn base_inv_freq(theta: f32, head_dim: usize) -> Vec<f32> {
+    (0..head_dim / 2).map(|i| 1. / theta.powf((2 * i) as f32 / head_dim as f32)).collect()
+}
+
+// Llama-3: high frequencies are kept, low frequencies divided by factor, and the band between
+// them interpolated smoothly
+fn llama3_inv_freq(inv_freq: &mut [f32], scaling: &RopeScaling, original: usize) {
+    let low_freq_factor = scaling.low_freq_factor.unwrap_or(1.);
+    let high_freq_factor = scaling.high_freq_factor.unwrap_or(4.);
+    let low_freq_wavelen = original as f32 / low_freq_factor;
+    let high_freq_wavelen = original as f32 / high_freq_factor;
+    for freq in inv_freq.iter_mut() {
+        let wavelen = 2. * PI / *freq;
+        if wavelen > low_freq_wavelen {
+            *freq /= scaling.factor;
+        } else if wavelen >= high_freq_wavelen {
+            let smooth = (original as f32 / wavelen - low_freq_factor) / (high_freq_factor - low_freq_factor);
+            *freq = (1. - smooth) * *freq / scaling.factor + smooth * *freq;
+        }
+    }
+}
+
+// YaRN: pairs that rotate more than beta_fast times over the original context keep their
+// frequency, pairs that rotate less than beta_slow times are interpolated by factor, with a
+// linear ramp in between. Returns the attention factor cos/sin are scaled by.
+fn yarn_inv_freq(inv_freq: &mut [f32], scaling: &RopeScaling, theta: f32, head_dim: usize, original: usize) -> f32 {
+    let correction_dim =
+        |rotations: f32| head_dim as f32 * (original as f32 / (rotations * 2. * PI)).ln() / (2. * theta.ln());
+    let low = correction_dim(scaling.beta_fast.unwrap_or(32.)).floor().max(0.);
+    let mut high = correction_dim(scaling.beta_slow.unwrap_or(1.)).ceil().min(head_dim as f32 - 1.);
+    if high == low {
+        high += 0.001;
+    }
+    for (i, freq) in inv_freq.iter_mut().enumerate() {
+        let extrapolation = ((i as f32 - low) / (high - low)).clamp(0., 1.);
+        *freq = *freq / scaling.factor * extrapolation + *freq * (1. - extrapolation);
+    }
+    let mscale = |scale: f32| if scaling.factor <= 1. { 1. } else { 0.1 * scale * scaling.factor.ln() + 1. };
+    scaling.attention_factor.unwrap_or(match (scaling.mscale, scaling.mscale_all_dim) {
+        (Some(m), Some(all)) => mscale(m) / mscale(all),
+        (Some(m), None) => mscale(m),
+        _ => mscale(1.),
+    })
+}
+
+impl RopeTable {
+    pub fn new(config: &LlamaConfigJson) -> Self {
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let half = head_dim / 2;
+        let positions = config.max_position_embeddings;
+        let theta = config.rope_theta;
+        let scaling = config.rope_scaling.as_ref();
+        let original = scaling
+            .and_then(|s| s.original_max_position_embeddings)
+            .unwrap_or(config.max_position_embeddings);
+
+        let mut inv_freq = base_inv_freq(theta, head_dim);
+        let mut attention_factor = 1.;
+        match scaling.map(|s| (s.kind(), s)) {
+            None | Some((RopeType::Default, _)) => {}
+            Some((RopeType::Linear, s)) => inv_freq.iter_mut().for_each(|f| *f /= s.factor),
+            Some((RopeType::Llama3, s)) => llama3_inv_freq(&mut inv_freq, s, original),
+            Some((RopeType::Yarn, s)) => attention_factor = yarn_inv_freq(&mut inv_freq, s, theta, head_dim, original),
+            Some((RopeType::Dynamic, s)) => {
+                // Dynamic NTK raises the base once the context outgrows the original length.
+                // Each position uses the base for a context ending at that position, i.e. the
+                // one it gets when decoded token by token, so prefill and incremental decoding
+                // agree and cached keys never need re-rotating.
+                return Self::build(half, positions, attention_factor, |pos| {
+                    let len = pos + 1;
+                    if len <= original {
+                        return inv_freq.clone();
+                    }
+                    let scale = s.factor * len as f32 / original as f32 - (s.factor - 1.);
+                    let base = theta * scale.powf(head_dim as f32 / (head_dim as f32 - 2.));
+                    base_inv_freq(base, head_dim)
+                });
+            }
+        }
+        Self::build(half, positions, attention_factor, |_| inv_freq.clone())
+    }
+
+    fn build(half: usize, positions: usize, attention_factor: f32, inv_freq: impl Fn(usize) -> Vec<f32>) -> Self {
+        let mut cos = Vec::with_capacity(positions * half);
+        let mut sin = Vec::with_capacity(positions * half);
+        for pos in 0..positions {
+            for freq in inv_freq(pos) {
+                let (s, c) = (pos as f32 * freq).sin_cos();
+                cos.push(c * attention_factor);
+                sin.push(s * attention_factor);
+            }
+        }
+        RopeTable { half, cos, sin }
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.half * 2
+    }
+
+    // (cos, sin) of every rotated pair at pos
+    pub fn at(&self, pos: usize) -> (&[f32], &[f32]) {
+        let start = pos * self.half;
+        (&self.cos[start..][..self.half], &self.sin[start..][..self.half])
+    }
+}
+
+#[cfg(test)]
+fn test_config(rope_scaling: &str) -> LlamaConfigJson {
+    let json = format!(
+        r#"{{"bos_token_id": 1, "eos_token_id": 2, "hidden_size": 64, "intermediate_size": 16,
+        "max_position_embeddings": 256, "num_attention_heads": 2, "num_hidden_layers": 1,
+        "num_key_value_heads": 2, "vocab_size": 10, "torch_dtype": "float32",
+        "rope_theta": 10000.0, "rope_scaling": {rope_scaling}}}"#
+    );
+    serde_json::from_str(&json).unwrap()
+}
+
+#[test]
+fn test_rope_table() {
+    let plain = RopeTable::new(&test_config("null"));
+    let (cos, sin) = plain.at(7);
+    assert_eq!(plain.head_dim(), 32);
+    for i in 0..16 {
+        let angle = 7. / 10000f32.powf((2 * i) as f32 / 32.);
+        assert!((cos[i] - angle.cos()).abs() < 1e-5 && (sin[i] - angle.sin()).abs() < 1e-5);
+    }
+    // linear: position p behaves like p / factor without scaling
+    let linear = RopeTable::new(&test_config(r#"{"type": "linear", "factor": 4.0}"#));
+    assert_eq!(linear.at(28), plain.at(7));
+    // dynamic NTK only changes positions past the original context
+    let dynamic = RopeTable::new(&test_config(
+        r#"{"rope_type": "dynamic", "factor": 2.0, "original_max_position_embeddings": 128}"#,
+    ));
+    assert_eq!(dynamic.at(127), plain.at(127));
+    assert_ne!(dynamic.at(200), plain.at(200));
+    assert_eq!(dynamic.at(200).0[0], plain.at(200).0[0]); // the highest frequency pair is unchanged
+}
+
+#[test]
+fn test_rope_scaling_frequencies() {
+    use crate::tensor::float_eq;
+    let plain = base_inv_freq(10000., 64);
+    // Llama-3: wavelengths under original / high_freq_factor are kept, over original /
+    // low_freq_factor divided by factor
+    let scaling: RopeScaling = serde_json::from_str(
+        r#"{"rope_type": "llama3", "factor": 8.0, "low_freq_factor": 1.0, "high_freq_factor": 4.0,
+        "original_max_position_embeddings": 128}"#,
+    )
+    .unwrap();
+    let mut inv_freq = plain.clone();
+    llama3_inv_freq(&mut inv_freq, &scaling, 128);
+    for (scaled, freq) in inv_freq.iter().zip(&plain) {
+        let wavelen = 2. * PI / freq;
+        if wavelen < 32. {
+            assert_eq!(scaled, freq);
+        } else if wavelen > 128. {
+            assert!(float_eq(scaled, &(freq / 8.), 1e-6));
+        } else {
+            assert!(scaled <= freq && *scaled >= freq / 8.);
+        }
+    }
+    // YaRN: the fastest pair is extrapolated, the slowest interpolated, with the default
+    // attention factor 0.1 ln(factor) + 1
+    let scaling: RopeScaling =
+        serde_json::from_str(r#"{"type": "yarn", "rope_type": "yarn", "factor": 4.0, "original_max_position_embeddings": 128}"#)
+            .unwrap();
+    let mut inv_freq = plain.clone();
+    let attention_factor = yarn_inv_freq(&mut inv_freq, &scaling, 10000., 64, 128);
+    assert_eq!(inv_freq[0], plain[0]);
+    assert!(float_eq(&inv_freq[31], &(plain[31] / 4.), 1e-6));
+    assert!(float_eq(&attention_factor, &(0.1 * 4f32.ln() + 1.), 1e-6));
+    let table = RopeTable::new(&test_config(
+        r#"{"type": "yarn", "factor": 4.0, "original_max_position_embeddings": 128}"#,
+    ));
+    assert!(float_eq(&table.at(0).0[0], &attention_factor, 1e-6));
+}
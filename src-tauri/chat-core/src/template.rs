@@ -0,0 +1,28 @@
+// ChatML prompt formatting shared by every front end of the model
+
+// Conversation start every new chat is prefilled with
+pub const PREAMBLE: &str = "<|im_start|>system
+  You are a highly knowledgeable and friendly assistant. Your goal is to understand and respond to user inquiries with clarity. Your interactions are always respectful, helpful, and focused on delivering the most accurate information to the user.<|im_end|>
+<|im_start|>user
+  Hey! Got a question for you!<|im_end|>
+<|im_start|>assistant";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+}
+
+// One more user question appended to a conversation whose cache is already filled
+pub fn user_turn(question: &str) -> String {
+    format!("<|im_start|>user\n{}\n<|im_end|>\n<|im_start|>assistant", question.trim())
+}
+
+// A whole conversation, ending with the prompt for the assistant's next answer
+pub fn chat_prompt(messages: &[Message]) -> String {
+    let mut prompt = String::new();
+    for m in messages {
+        prompt += &format!("<|im_start|>{}\n{}<|im_end|>\n", m.role, m.content.trim());
+    }
+    prompt + "<|im_start|>assistant"
+}
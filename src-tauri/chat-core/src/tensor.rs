@@ -0,0 +1,398 @@
+use std::ops::{Deref, DerefMut};
+use std::{fmt, sync::Arc, vec};
+
+// An n-dimensional view over a shared buffer: element (i0, i1, ..) lives at
+// offset + i0 * strides[0] + i1 * strides[1] + ... Views made by transpose, permute, narrow and
+// broadcast_to share the buffer; contiguous() materializes one into row-major order.
+// Clones and views are copy-on-write: data_mut() copies the buffer first if another tensor
+// still refers to it, so a write never shows through any other tensor.
+#[derive(Clone)]
+pub struct Tensor<T> {
+    data: Arc<Box<[T]>>,
+    shape: Dims,
+    strides: Dims, // 0 along broadcast dimensions
+    offset: usize,
+    length: usize, // number of elements, the product of shape
+}
+
+pub const MAX_DIMS: usize = 6;
+
+// A shape or strides, stored inline so that making a view never allocates
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Dims {
+    len: usize,
+    dims: [usize; MAX_DIMS],
+}
+
+impl Dims {
+    fn remove(&mut self, i: usize) {
+        self.dims.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+    }
+}
+
+impl From<&[usize]> for Dims {
+    fn from(dims: &[usize]) -> Self {
+        dims.iter().copied().collect()
+    }
+}
+
+impl FromIterator<usize> for Dims {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut d = Dims { len: 0, dims: [0; MAX_DIMS] };
+        for n in iter {
+            assert!(d.len < MAX_DIMS, "tensors have at most {MAX_DIMS} dimensions");
+            d.dims[d.len] = n;
+            d.len += 1;
+        }
+        d
+    }
+}
+
+impl Deref for Dims {
+    type Target = [usize];
+    fn deref(&self) -> &[usize] {
+        &self.dims[..self.len]
+    }
+}
+
+impl DerefMut for Dims {
+    fn deref_mut(&mut self) -> &mut [usize] {
+        &mut self.dims[..self.len]
+    }
+}
+
+impl fmt::Debug for Dims {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+// Strides of a row-major tensor of this shape
+fn row_major(shape: &[usize]) -> Dims {
+    let mut strides: Dims = shape.iter().map(|_| 1).collect();
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+impl<T: Copy + Clone + Default> Tensor<T> {
+    pub fn new(data: Vec<T>, shape: &[usize]) -> Self {
+        let length = data.len();
+        assert!(length == shape.iter().product::<usize>(), "{length} elements do not fit shape {shape:?}");
+        Tensor {
+            data: Arc::new(data.into_boxed_slice()),
+            shape: shape.into(),
+            strides: row_major(shape),
+            offset: 0,
+            length,
+        }
+    }
+
+    pub fn default(shape: &[usize]) -> Self {
+        let length = shape.iter().product();
+        let data = vec![T::default(); length];
+        Self::new(data, shape)
+    }
+
+    // The elements of a contiguous tensor; views that are not must go through contiguous() first
+    pub fn data(&self) -> &[T] {
+        assert!(self.is_contiguous(), "data() of a strided view {:?} / {:?}", self.shape, self.strides);
+        &self.data[self.offset..][..self.length]
+    }
+
+    pub fn data_mut(&mut self) -> &mut [T] {
+        assert!(self.is_contiguous(), "data_mut() of a strided view {:?} / {:?}", self.shape, self.strides);
+        let data = Arc::make_mut(&mut self.data);
+        &mut data[self.offset..][..self.length]
+    }
+
+    // Whether data_mut() can write in place, i.e. no other tensor refers to the buffer
+    pub fn is_unique(&self) -> bool {
+        Arc::strong_count(&self.data) == 1
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn size(&self) -> usize {
+        self.length
+    }
+
+    // Row-major with no gaps, so data() can hand out the elements as one slice. Strides of
+    // dimensions of size 1 don't matter.
+    pub fn is_contiguous(&self) -> bool {
+        let expected = row_major(&self.shape);
+        self.shape.iter().zip(self.strides.iter().zip(expected.iter())).all(|(&n, (s, e))| n == 1 || s == e)
+    }
+
+    // Reinterpret the tensor as a new shape while preserving total size. Only contiguous
+    // tensors can be reshaped; call contiguous() on a strided view first.
+    pub fn reshape(&mut self, new_shape: &[usize]) -> &mut Self {
+        let new_length: usize = new_shape.iter().product();
+        if new_length != self.length {
+            let old_shape = self.shape;
+            panic!("New shape {new_shape:?} does not match tensor of {old_shape:?}");
+        }
+        assert!(self.is_contiguous(), "reshape of a strided view {:?} / {:?}", self.shape, self.strides);
+        self.shape = new_shape.into();
+        self.strides = row_major(new_shape);
+        self
+    }
+
+    // Re-views the buffer of a scratch tensor as `shape`, which may hold fewer elements than
+    // before; only allocates when the buffer is too small. Contents are left as they were.
+    pub fn reuse(&mut self, shape: &[usize]) {
+        let length = shape.iter().product();
+        if self.offset != 0 || length > self.data.len() {
+            *self = Tensor::default(shape);
+            return;
+        }
+        self.shape = shape.into();
+        self.strides = row_major(shape);
+        self.length = length;
+    }
+
+    // Same as reshape, as a new view of the same buffer
+    pub fn view(&self, shape: &[usize]) -> Self {
+        let mut t = self.clone();
+        t.reshape(shape);
+        t
+    }
+
+    pub fn slice(&self, start: usize, shape: &[usize]) -> Self {
+        let new_length: usize = shape.iter().product();
+        assert!(self.is_contiguous());
+        assert!(start + new_length <= self.length);
+        Tensor {
+            data: self.data.clone(),
+            shape: shape.into(),
+            strides: row_major(shape),
+            offset: self.offset + start,
+            length: new_length,
+        }
+    }
+
+    // Swaps two dimensions
+    pub fn transpose(&self, dim0: usize, dim1: usize) -> Self {
+        let mut t = self.clone();
+        t.shape.swap(dim0, dim1);
+        t.strides.swap(dim0, dim1);
+        t
+    }
+
+    // Reorders the dimensions: dimension i of the view is dimension dims[i] of self
+    pub fn permute(&self, dims: &[usize]) -> Self {
+        assert!(dims.len() == self.shape.len(), "permute {dims:?} of a tensor of {:?}", self.shape);
+        assert!((0..dims.len()).all(|d| dims.contains(&d)), "{dims:?} is not a permutation");
+        let mut t = self.clone();
+        t.shape = dims.iter().map(|&d| self.shape[d]).collect();
+        t.strides = dims.iter().map(|&d| self.strides[d]).collect();
+        t
+    }
+
+    // Elements start..start + len along dim
+    pub fn narrow(&self, dim: usize, start: usize, len: usize) -> Self {
+        assert!(start + len <= self.shape[dim], "narrow {start}..{} of dimension {dim} of {:?}", start + len, self.shape);
+        let mut t = self.clone();
+        t.offset += start * self.strides[dim];
+        t.shape[dim] = len;
+        t.length = t.shape.iter().product();
+        t
+    }
+
+    // Index `index` along dim, dropping that dimension
+    pub fn select(&self, dim: usize, index: usize) -> Self {
+        let mut t = self.narrow(dim, index, 1);
+        t.shape.remove(dim);
+        t.strides.remove(dim);
+        t
+    }
+
+    // Repeats the tensor to `shape` without copying: dimensions are matched from the right,
+    // and those of size 1 or missing on the left are repeated with stride 0
+    pub fn broadcast_to(&self, shape: &[usize]) -> Self {
+        assert!(shape.len() >= self.shape.len(), "cannot broadcast {:?} to {shape:?}", self.shape);
+        let extra = shape.len() - self.shape.len();
+        let strides = shape
+            .iter()
+            .enumerate()
+            .map(|(i, &n)| match i.checked_sub(extra) {
+                Some(j) if self.shape[j] == n => self.strides[j],
+                Some(j) if self.shape[j] == 1 => 0,
+                None => 0,
+                Some(_) => panic!("cannot broadcast {:?} to {shape:?}", self.shape),
+            })
+            .collect();
+        Tensor {
+            data: self.data.clone(),
+            shape: shape.into(),
+            strides,
+            offset: self.offset,
+            length: shape.iter().product(),
+        }
+    }
+
+    // The same tensor in row-major order: self if it already is, otherwise a copy
+    pub fn contiguous(&self) -> Self {
+        if self.is_contiguous() {
+            return self.clone();
+        }
+        Tensor::new(self.iter().collect(), &self.shape)
+    }
+
+    pub fn get(&self, index: &[usize]) -> T {
+        assert!(index.len() == self.shape.len() && index.iter().zip(self.shape.iter()).all(|(i, n)| i < n));
+        let pos: usize = index.iter().zip(self.strides.iter()).map(|(i, s)| i * s).sum();
+        self.data[self.offset + pos]
+    }
+
+    // Elements in row-major order of the view
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        self.positions().map(|pos| self.data[pos])
+    }
+
+    // Buffer position of every element, in row-major order of the view
+    pub(crate) fn positions(&self) -> impl Iterator<Item = usize> {
+        let (shape, strides) = (self.shape, self.strides);
+        let mut index = [0; MAX_DIMS];
+        let mut pos = self.offset;
+        (0..self.length).map(move |i| {
+            if i > 0 {
+                for dim in (0..shape.len()).rev() {
+                    index[dim] += 1;
+                    pos += strides[dim];
+                    if index[dim] < shape[dim] {
+                        break;
+                    }
+                    pos -= strides[dim] * index[dim];
+                    index[dim] = 0;
+                }
+            }
+            pos
+        })
+    }
+
+    // The whole shared buffer, for operators that walk a view by its strides
+    pub(crate) fn storage(&self) -> &[T] {
+        &self.data
+    }
+}
+
+// Some helper functions for testing and debugging
+impl Tensor<f32> {
+    #[allow(unused)]
+    pub fn close_to(&self, other: &Self, rel: f32) -> bool {
+        if self.shape() != other.shape() {
+            return false;
+        }
+        return self.iter().zip(other.iter()).all(|(x, y)| float_eq(&x, &y, rel));
+    }
+    #[allow(unused)]
+    pub fn print(&self) {
+        println!(
+            "shpae: {:?}, offset: {}, length: {}",
+            self.shape, self.offset, self.length
+        );
+        let dim = self.shape()[self.shape().len() - 1];
+        let t = self.contiguous();
+        for row in t.data().chunks(dim) {
+            println!("{:?}", row);
+        }
+    }
+}
+
+#[inline]
+pub fn float_eq(x: &f32, y: &f32, rel: f32) -> bool {
+    (x - y).abs() <= rel * (x.abs() + y.abs()) / 2.0
+}
+
+#[test]
+fn test_views() {
+    let t = Tensor::<f32>::new((0..24).map(|x| x as f32).collect(), &[2, 3, 4]);
+    let p = t.permute(&[2, 0, 1]); // (4, 2, 3)
+    assert_eq!(p.shape(), [4, 2, 3]);
+    assert!(!p.is_contiguous());
+    assert_eq!(p.get(&[3, 1, 2]), t.get(&[1, 2, 3]));
+    assert_eq!(p.contiguous().data()[..6], [0., 4., 8., 12., 16., 20.]);
+    let tr = t.transpose(1, 2); // (2, 4, 3)
+    assert_eq!(tr.iter().take(4).collect::<Vec<_>>(), [0., 4., 8., 1.]);
+    // narrowing the first dimension keeps the view contiguous, inner ones do not
+    assert_eq!(t.narrow(0, 1, 1).data()[0], 12.);
+    let n = t.narrow(2, 1, 2);
+    assert!(!n.is_contiguous());
+    assert_eq!(n.iter().take(4).collect::<Vec<_>>(), [1., 2., 5., 6.]);
+    assert_eq!(t.select(1, 2).contiguous().data(), [8., 9., 10., 11., 20., 21., 22., 23.]);
+    // broadcasting repeats along size-1 and new leading dimensions with stride 0
+    let row = Tensor::<f32>::new(vec![1., 2.], &[1, 2]);
+    let b = row.broadcast_to(&[2, 3, 2]);
+    assert_eq!(b.strides(), [0, 0, 1]);
+    assert_eq!(b.contiguous().data(), [1., 2., 1., 2., 1., 2., 1., 2., 1., 2., 1., 2.]);
+}
+
+#[test]
+#[should_panic]
+fn test_reshape_strided_view() {
+    let t = Tensor::<f32>::default(&[2, 3]);
+    t.transpose(0, 1).view(&[6]);
+}
+
+#[test]
+fn test_copy_on_write() {
+    let mut a = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[2, 2]);
+    let ptr = a.data().as_ptr();
+    a.data_mut()[0] = 0.; // unique: written in place
+    assert_eq!(a.data().as_ptr(), ptr);
+    let b = a.clone();
+    let mut row = a.slice(2, &[1, 2]);
+    assert!(!a.is_unique());
+    a.data_mut()[1] = 5.;
+    row.data_mut()[0] = 6.;
+    assert_eq!(a.data(), [0., 5., 3., 4.]);
+    assert_eq!(b.data(), [0., 2., 3., 4.]);
+    assert_eq!(row.data(), [6., 4.]);
+    drop(b);
+    drop(row);
+    assert!(a.is_unique());
+}
+
+#[test]
+fn test_copy_on_write_threads() {
+    // views sent to other threads write to their own copies, and the original buffer is
+    // freed by whichever tensor drops it last
+    let t = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+    let handles: Vec<_> = (0..2)
+        .map(|i| {
+            let mut row = t.slice(i * 3, &[1, 3]);
+            std::thread::spawn(move || {
+                row.data_mut()[0] = 10. * (i + 1) as f32;
+                row
+            })
+        })
+        .collect();
+    let rows: Vec<Tensor<f32>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(rows[0].data(), [10., 2., 3.]);
+    assert_eq!(rows[1].data(), [20., 5., 6.]);
+    assert_eq!(t.data(), [1., 2., 3., 4., 5., 6.]);
+    drop(t);
+    assert!(rows.iter().all(Tensor::is_unique));
+}
+
+#[test]
+fn test_reuse_shared_buffer() {
+    let mut scratch = Tensor::<f32>::new(vec![1., 2., 3., 4.], &[4]);
+    let view = scratch.slice(0, &[2]);
+    scratch.reuse(&[1, 2]); // fits: still the shared buffer
+    assert!(!scratch.is_unique());
+    scratch.data_mut().copy_from_slice(&[7., 8.]);
+    assert_eq!(view.data(), [1., 2.]);
+    assert_eq!(scratch.data(), [7., 8.]);
+    scratch.reuse(&[3, 2]); // too small: a new buffer
+    assert_eq!(scratch.data(), [0.; 6]);
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::Tokenizer;
+
+// Raw bytes every token id contributes to the decoded text, indexed by id.
+// Special tokens (<|im_end|>, </s>, ...) and unknown ids map to an empty Vec.
+pub fn token_bytes(tokenizer: &Tokenizer) -> Vec<Vec<u8>> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let special: Vec<u32> = tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(_, token)| token.special)
+        .map(|(id, _)| id)
+        .collect();
+    let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+    let unicode_to_byte = if byte_level { unicode_to_bytes() } else { HashMap::new() };
+
+    (0..vocab_size as u32)
+        .map(|id| {
+            if special.contains(&id) {
+                return Vec::new();
+            }
+            let piece = match tokenizer.id_to_token(id) {
+                Some(piece) => piece,
+                None => return Vec::new(),
+            };
+            if byte_level {
+                piece.chars().filter_map(|c| unicode_to_byte.get(&c).copied()).collect()
+            } else if let Some(byte) = byte_fallback(&piece) {
+                vec![byte]
+            } else {
+                // sentencepiece marks word starts with ▁
+                piece.replace('\u{2581}', " ").into_bytes()
+            }
+        })
+        .collect()
+}
+
+// Turns token ids back into text the way tokenizer.decode(ids, true) does, but one id at a time:
+// token_bytes of every id, and whether the decoder drops the space sentencepiece puts before
+// the first word. Tokenizers without a decoder join tokens with spaces.
+pub struct TokenDecoder {
+    bytes: Vec<Vec<u8>>,
+    strip_leading_space: bool,
+    join_with_spaces: bool,
+    byte_level: bool, // invalid bytes become one U+FFFD per sequence, not one per byte
+}
+
+impl TokenDecoder {
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let bytes = token_bytes(tokenizer);
+        // whether a word-start token decoded on its own keeps its space
+        let word_start = bytes.iter().position(|b| b.len() > 1 && b[0] == b' ' && b[1].is_ascii_alphanumeric());
+        let strip_leading_space = word_start.is_some_and(|id| {
+            tokenizer.decode(&[id as u32], true).unwrap().as_bytes() == &bytes[id][1..]
+        });
+        let join_with_spaces = tokenizer.get_decoder().is_none();
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        TokenDecoder { bytes, strip_leading_space, join_with_spaces, byte_level }
+    }
+
+    pub fn detokenizer(&self) -> Detokenizer<'_> {
+        Detokenizer { decoder: self, pending: Vec::new(), started: false }
+    }
+
+    pub fn decode(&self, ids: &[u32]) -> String {
+        let mut detokenizer = self.detokenizer();
+        let mut text: String = ids.iter().map(|&id| detokenizer.push(id)).collect();
+        text.push_str(&detokenizer.finish());
+        text
+    }
+}
+
+// Decoding of one generated sequence. Byte-fallback and byte-level tokens can split a character
+// across tokens, so bytes that do not complete a UTF-8 character yet are held back.
+pub struct Detokenizer<'a> {
+    decoder: &'a TokenDecoder,
+    pending: Vec<u8>, // start of a character still missing bytes
+    started: bool,    // some text has been produced
+}
+
+impl Detokenizer<'_> {
+    // The text token id completes, possibly empty
+    pub fn push(&mut self, id: u32) -> String {
+        let decoder = self.decoder;
+        let bytes = match decoder.bytes.get(id as usize) {
+            Some(bytes) if !bytes.is_empty() => bytes.as_slice(),
+            _ => return String::new(),
+        };
+        if !self.started {
+            self.started = true;
+            let strip = decoder.strip_leading_space && bytes[0] == b' ';
+            self.pending.extend_from_slice(if strip { &bytes[1..] } else { bytes });
+        } else {
+            if decoder.join_with_spaces {
+                self.pending.push(b' ');
+            }
+            self.pending.extend_from_slice(bytes);
+        }
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.pending.clear();
+                    return text;
+                }
+                Err(e) => {
+                    let valid = e.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.pending[..valid]).unwrap());
+                    match e.error_len() {
+                        // bytes that can never form a character, replaced as decode does
+                        Some(invalid) => {
+                            text.push_str(&self.replacement(invalid));
+                            self.pending.drain(..valid + invalid);
+                        }
+                        // an unfinished character: wait for the next tokens
+                        None => {
+                            self.pending.drain(..valid);
+                            return text;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // What is left once the sequence has ended: an unfinished character, as U+FFFD
+    pub fn finish(&mut self) -> String {
+        let text = match self.pending.is_empty() {
+            true => String::new(),
+            false => self.replacement(self.pending.len()),
+        };
+        self.pending.clear();
+        text
+    }
+
+    fn replacement(&self, n_bytes: usize) -> String {
+        let n = if self.decoder.byte_level { 1 } else { n_bytes };
+        char::REPLACEMENT_CHARACTER.to_string().repeat(n)
+    }
+}
+
+// "<0x0A>" style pieces used by sentencepiece byte fallback
+fn byte_fallback(piece: &str) -> Option<u8> {
+    let hex = piece.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+// Inverse of the GPT-2 byte-level BPE table, which maps every byte to a printable char
+fn unicode_to_bytes() -> HashMap<char, u8> {
+    let mut printable: Vec<u32> = ('!' as u32..='~' as u32)
+        .chain('¡' as u32..='¬' as u32)
+        .chain('®' as u32..='ÿ' as u32)
+        .collect();
+    let mut chars = printable.clone();
+    let mut n = 0;
+    for b in 0..256 {
+        if !printable.contains(&b) {
+            printable.push(b);
+            chars.push(256 + n);
+            n += 1;
+        }
+    }
+    printable
+        .into_iter()
+        .zip(chars)
+        .map(|(b, c)| (char::from_u32(c).unwrap(), b as u8))
+        .collect()
+}
+
+#[test]
+fn test_unicode_to_bytes() {
+    let table = unicode_to_bytes();
+    assert_eq!(table.len(), 256);
+    assert_eq!(table[&'Ġ'], b' ');
+    assert_eq!(table[&'Ċ'], b'\n');
+    assert_eq!(table[&'a'], b'a');
+    assert_eq!(byte_fallback("<0xE4>"), Some(0xE4));
+    assert_eq!(byte_fallback("<0x>"), None);
+}
+
+#[cfg(test)]
+fn test_tokenizer(model: &str, decoder: &str) -> Tokenizer {
+    let json = format!(
+        r#"{{"version": "1.0", "truncation": null, "padding": null,
+        "added_tokens": [{{"id": 0, "content": "</s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}}],
+        "normalizer": null, "pre_tokenizer": null, "post_processor": null, "decoder": {decoder},
+        "model": {{"type": "BPE", "dropout": null, "unk_token": null, "continuing_subword_prefix": null, "end_of_word_suffix": null,
+        "fuse_unk": false, "byte_fallback": true, "ignore_merges": false, "vocab": {model}, "merges": []}}}}"#
+    );
+    json.parse().unwrap()
+}
+
+#[test]
+fn test_detokenizer() {
+    // sentencepiece with byte fallback: 好 is E5 A5 BD, split over three byte tokens
+    let tokenizer = test_tokenizer(
+        r#"{"</s>": 0, "▁hello": 1, "▁world": 2, "你": 3, "<0xE5>": 4, "<0xA5>": 5, "<0xBD>": 6}"#,
+        r#"{"type": "Sequence", "decoders": [{"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+        {"type": "ByteFallback"}, {"type": "Fuse"}, {"type": "Strip", "content": " ", "start": 1, "stop": 0}]}"#,
+    );
+    let decoder = TokenDecoder::new(&tokenizer);
+    let mut detokenizer = decoder.detokenizer();
+    let pieces: Vec<String> = [1, 2, 3, 4, 5, 6, 0].iter().map(|&id| detokenizer.push(id)).collect();
+    assert_eq!(pieces, ["hello", " world", "你", "", "", "好", ""]);
+    for ids in [vec![1, 2, 3, 4, 5, 6, 0], vec![2, 1], vec![3, 1], vec![4, 1, 5, 6], vec![4, 5, 1], vec![1, 4, 5]] {
+        assert_eq!(decoder.decode(&ids), tokenizer.decode(&ids, true).unwrap(), "{ids:?}");
+    }
+
+    // byte-level BPE keeps the space of the first word; 好 is split over two tokens
+    let tokenizer = test_tokenizer(
+        r#"{"</s>": 0, "Ġhello": 1, "Ġworld": 2, "ä½ł": 3, "å¥": 4, "½": 5}"#,
+        r#"{"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true}"#,
+    );
+    let decoder = TokenDecoder::new(&tokenizer);
+    let mut detokenizer = decoder.detokenizer();
+    let pieces: Vec<String> = [1, 3, 4, 5, 2].iter().map(|&id| detokenizer.push(id)).collect();
+    assert_eq!(pieces, [" hello", "你", "", "好", " world"]);
+    assert_eq!(detokenizer.push(4), "");
+    assert_eq!(detokenizer.finish(), "\u{FFFD}");
+    for ids in [vec![1, 3, 4, 5, 2, 0], vec![3, 1]] {
+        assert_eq!(decoder.decode(&ids), tokenizer.decode(&ids, true).unwrap(), "{ids:?}");
+    }
+}
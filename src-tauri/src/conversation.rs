@@ -0,0 +1,410 @@
+// Conversation tree of one chat session. Every question asked stays in the tree as a turn:
+// withdrawing or editing a question only moves the active path back to its parent, so the
+// next question starts a new branch and earlier branches can be switched back to.
+// Regenerating an answer adds a sibling turn with the same id and question, so every answer
+// to a question stays selectable.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chat_core::generate::GenerateOptions;
+
+// 对话树中的一轮问答, 返回给前端
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct TurnInfo {
+    pub id: String,
+    pub parent: Option<String>,
+    pub question: String,
+    pub answer: String,
+    pub active: bool,       // 是否在当前分支上
+    pub alternative: usize, // 同一问题的第几个答案
+    pub alternatives: usize, // 同一问题的答案总数
+    pub time: u64,          // 回答时间, unix 秒
+}
+
+// One turn of an exported conversation; parents are indices into the exported turns since
+// the answers to one question share its id
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ExportedTurn {
+    pub id: String,
+    pub parent: Option<usize>,
+    pub question: String,
+    pub answer: String,
+    pub options: GenerateOptions,
+    pub time: u64,
+    pub active: bool,
+}
+
+// 导出的对话, JSON 格式
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ExportedConversation {
+    pub name: String,
+    pub model: String,
+    pub exported_at: u64,
+    #[serde(default)]
+    pub options: GenerateOptions,
+    pub turns: Vec<ExportedTurn>,
+}
+
+#[derive(Clone)]
+struct Turn {
+    id: String,
+    question: String,
+    answer: String,
+    options: GenerateOptions,
+    parent: Option<usize>,
+    selected: Option<usize>, // child the active path continues with
+    prompt_len: usize,       // tokens of the user turn at the start of `tokens`
+    tokens: Vec<u32>,        // exactly the tokens this turn added to the KV cache
+    time: u64,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[derive(Clone)]
+pub struct Conversation {
+    pub options: GenerateOptions, // used for questions asked without options of their own
+    pub created: u64,
+    base_len: usize, // cache length of the preamble every branch starts from
+    turns: Vec<Turn>,
+    selected: Option<usize>, // first turn of the active path
+}
+
+impl Conversation {
+    pub fn new(base_len: usize) -> Self {
+        Conversation { options: GenerateOptions::default(), created: now(), base_len, turns: Vec::new(), selected: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.turns.len()
+    }
+
+    // Time of the latest answer, or of creation
+    pub fn updated(&self) -> u64 {
+        self.turns.iter().map(|turn| turn.time).max().unwrap_or(self.created)
+    }
+
+    // Turns of the active branch, from the first question to the latest answer
+    fn path(&self) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut next = self.selected;
+        while let Some(turn) = next {
+            path.push(turn);
+            next = self.turns[turn].selected;
+        }
+        path
+    }
+
+    // Every answer to question `id`, in the order they were generated
+    fn alternatives(&self, id: &str) -> Vec<usize> {
+        (0..self.turns.len()).filter(|&turn| self.turns[turn].id == id).collect()
+    }
+
+    // The answer to question `id` on the active branch, else the latest one
+    fn position(&self, id: &str) -> Option<usize> {
+        let path = self.path();
+        let alternatives = self.alternatives(id);
+        alternatives.iter().find(|turn| path.contains(turn)).or(alternatives.last()).copied()
+    }
+
+    fn select(&mut self, parent: Option<usize>, child: Option<usize>) {
+        match parent {
+            Some(parent) => self.turns[parent].selected = child,
+            None => self.selected = child,
+        }
+    }
+
+    // Cache length after the first n turns of `path`
+    fn cache_len(&self, path: &[usize], n: usize) -> usize {
+        self.base_len + path[..n].iter().map(|&turn| self.turns[turn].tokens.len()).sum::<usize>()
+    }
+
+    // Appends an answered question to the end of the active branch
+    pub fn push(&mut self, id: String, question: String, answer: String, options: GenerateOptions, prompt_len: usize, tokens: Vec<u32>) {
+        let parent = self.path().last().copied();
+        self.turns.push(Turn { id, question, answer, options, parent, selected: None, prompt_len, tokens, time: now() });
+        self.select(parent, Some(self.turns.len() - 1));
+    }
+
+    // What another answer to question `id` on the active branch starts from: the cache length
+    // at the start of the turn, the user turn tokens, the question and the options it was
+    // answered with. withdraw() the turn before push()ing the new answer.
+    pub fn regenerate(&self, id: &str) -> Option<(usize, Vec<u32>, String, GenerateOptions)> {
+        let turn = self.position(id)?;
+        let path = self.path();
+        let index = path.iter().position(|&t| t == turn)?;
+        let t = &self.turns[turn];
+        Some((self.cache_len(&path, index), t.tokens[..t.prompt_len].to_vec(), t.question.clone(), t.options.clone()))
+    }
+
+    // A new conversation holding the active branch up to and including turn `id`, with the
+    // cache length its KV cache keeps
+    pub fn fork(&self, id: &str) -> Option<(Self, usize)> {
+        let turn = self.position(id)?;
+        let path = self.path();
+        let index = path.iter().position(|&t| t == turn)?;
+        let mut fork = Conversation { created: now(), turns: Vec::new(), selected: None, ..self.clone() };
+        for &t in &path[..=index] {
+            let parent = fork.turns.len().checked_sub(1);
+            fork.turns.push(Turn { parent, selected: None, ..self.turns[t].clone() });
+            fork.select(parent, Some(fork.turns.len() - 1));
+        }
+        Some((fork, self.cache_len(&path, index + 1)))
+    }
+
+    // Every answer generated for question `id`
+    pub fn answers(&self, id: &str) -> Vec<String> {
+        self.alternatives(id).into_iter().map(|turn| self.turns[turn].answer.clone()).collect()
+    }
+
+    // Ends the active branch just before turn `id`, keeping the turn and everything after it as
+    // a branch of its own. Returns the cache length to truncate to, None if `id` is not on the
+    // active branch.
+    pub fn withdraw(&mut self, id: &str) -> Option<usize> {
+        let turn = self.position(id)?;
+        let path = self.path();
+        let index = path.iter().position(|&t| t == turn)?;
+        self.select(self.turns[turn].parent, None);
+        Some(self.cache_len(&path, index))
+    }
+
+    // Makes the branch through turn `id` active, continuing below it the way it was last left.
+    // Returns the cache length both branches share and the tokens to prefill after it.
+    pub fn switch(&mut self, id: &str) -> Option<(usize, Vec<u32>)> {
+        let turn = self.position(id)?;
+        self.switch_to(turn)
+    }
+
+    // Like switch(), to the index-th answer of question `id`
+    pub fn select_answer(&mut self, id: &str, index: usize) -> Option<(usize, Vec<u32>)> {
+        let turn = *self.alternatives(id).get(index)?;
+        self.switch_to(turn)
+    }
+
+    fn switch_to(&mut self, turn: usize) -> Option<(usize, Vec<u32>)> {
+        let old_path = self.path();
+        let mut child = turn;
+        while let Some(parent) = self.turns[child].parent {
+            self.turns[parent].selected = Some(child);
+            child = parent;
+        }
+        self.selected = Some(child);
+        let new_path = self.path();
+        let shared = old_path.iter().zip(&new_path).take_while(|(a, b)| a == b).count();
+        let prefill = new_path[shared..].iter().flat_map(|&t| self.turns[t].tokens.iter().copied()).collect();
+        Some((self.cache_len(&old_path, shared), prefill))
+    }
+
+    fn info(&self, turn: usize, active: bool) -> TurnInfo {
+        let t = &self.turns[turn];
+        let alternatives = self.alternatives(&t.id);
+        TurnInfo {
+            id: t.id.clone(),
+            parent: t.parent.map(|parent| self.turns[parent].id.clone()),
+            question: t.question.clone(),
+            answer: t.answer.clone(),
+            active,
+            alternative: alternatives.iter().position(|&a| a == turn).unwrap(),
+            alternatives: alternatives.len(),
+            time: t.time,
+        }
+    }
+
+    // The active branch, as the chat window shows it
+    pub fn active_path(&self) -> Vec<TurnInfo> {
+        self.path().into_iter().map(|turn| self.info(turn, true)).collect()
+    }
+
+    // The first len - base_len tokens the active branch put in the cache after the preamble,
+    // for rebuilding a cache that no longer holds them
+    pub fn active_tokens(&self, len: usize) -> Vec<u32> {
+        let tokens = self.path().into_iter().flat_map(|turn| self.turns[turn].tokens.iter().copied());
+        tokens.take(len.saturating_sub(self.base_len)).collect()
+    }
+
+    // Every turn of every branch, in the order they were asked
+    pub fn turns(&self) -> Vec<TurnInfo> {
+        let path = self.path();
+        (0..self.turns.len()).map(|turn| self.info(turn, path.contains(&turn))).collect()
+    }
+
+    pub fn export(&self) -> Vec<ExportedTurn> {
+        let path = self.path();
+        self.turns
+            .iter()
+            .enumerate()
+            .map(|(turn, t)| ExportedTurn {
+                id: t.id.clone(),
+                parent: t.parent,
+                question: t.question.clone(),
+                answer: t.answer.clone(),
+                options: t.options.clone(),
+                time: t.time,
+                active: path.contains(&turn),
+            })
+            .collect()
+    }
+
+    // Rebuilds an exported conversation on top of a cache holding base_len preamble tokens.
+    // `tokenize` gives the user turn tokens and the answer tokens of a turn. Returns the
+    // conversation and the tokens of its active branch, which the cache still needs.
+    pub fn import(
+        base_len: usize,
+        turns: &[ExportedTurn],
+        tokenize: impl Fn(&ExportedTurn) -> (Vec<u32>, Vec<u32>),
+    ) -> Result<(Self, Vec<u32>), String> {
+        let mut conversation = Conversation::new(base_len);
+        for (turn, t) in turns.iter().enumerate() {
+            if t.parent.is_some_and(|parent| parent >= turn) {
+                return Err(format!("turn {turn} ({}) has parent {:?}, which does not come before it", t.id, t.parent));
+            }
+            let (prompt, answer) = tokenize(t);
+            conversation.turns.push(Turn {
+                id: t.id.clone(),
+                question: t.question.clone(),
+                answer: t.answer.clone(),
+                options: t.options.clone(),
+                parent: t.parent,
+                selected: None,
+                prompt_len: prompt.len(),
+                tokens: [prompt, answer].concat(),
+                time: t.time,
+            });
+        }
+        let prefill = match turns.iter().rposition(|t| t.active) {
+            Some(last) => conversation.switch_to(last).unwrap().1,
+            None => Vec::new(),
+        };
+        Ok((conversation, prefill))
+    }
+}
+
+impl ExportedConversation {
+    // The active branch as Markdown, for reading and sharing
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n\n> 模型: {} · 导出时间: {}\n", self.name, self.model, format_time(self.exported_at));
+        for turn in self.turns.iter().filter(|turn| turn.active) {
+            markdown += &format!("\n---\n\n**问** ({})\n\n{}\n\n**答**\n\n{}\n", format_time(turn.time), turn.question.trim(), turn.answer.trim());
+        }
+        markdown
+    }
+}
+
+// Unix seconds as "YYYY-MM-DD HH:MM:SS UTC"
+fn format_time(secs: u64) -> String {
+    // days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let time = secs % 86400;
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC", time / 3600, time % 3600 / 60, time % 60)
+}
+
+#[test]
+fn test_branches() {
+    let mut conv = Conversation::new(10);
+    let push = |conv: &mut Conversation, id: &str, q: &str, a: &str, tokens: Vec<u32>| {
+        conv.push(id.into(), q.into(), a.into(), GenerateOptions::default(), 1, tokens)
+    };
+    push(&mut conv, "1", "q1", "a1", vec![1; 3]);
+    push(&mut conv, "2", "q2", "a2", vec![2; 4]);
+    push(&mut conv, "3", "q3", "a3", vec![3; 5]);
+    // withdrawing q2 keeps it, and the next question starts a sibling branch
+    assert_eq!(conv.withdraw("2"), Some(13));
+    assert_eq!(conv.withdraw("3"), None);
+    push(&mut conv, "4", "q2'", "a2'", vec![4; 2]);
+    let ids: Vec<String> = conv.active_path().into_iter().map(|turn| turn.id).collect();
+    assert_eq!(ids, ["1", "4"]);
+    assert_eq!(conv.turns().len(), 4);
+    assert_eq!(conv.turns()[3].parent.as_deref(), Some("1"));
+
+    // switching back restores the old branch down to where it was left
+    let (keep, prefill) = conv.switch("2").unwrap();
+    assert_eq!(keep, 13);
+    assert_eq!(prefill, [vec![2; 4], vec![3; 5]].concat());
+    let ids: Vec<String> = conv.active_path().into_iter().map(|turn| turn.id).collect();
+    assert_eq!(ids, ["1", "2", "3"]);
+
+    let (keep, prefill) = conv.switch("4").unwrap();
+    assert_eq!((keep, prefill), (13, vec![4; 2]));
+    assert!(conv.switch("5").is_none());
+}
+
+#[test]
+fn test_regenerate() {
+    let mut conv = Conversation::new(10);
+    let options = GenerateOptions { temperature: 0.5, ..GenerateOptions::default() };
+    conv.push("1".into(), "q1".into(), "a1".into(), options, 2, vec![1, 2, 3]);
+    conv.push("2".into(), "q2".into(), "a2".into(), GenerateOptions::default(), 1, vec![4, 5]);
+
+    let (start, prompt, question, options) = conv.regenerate("1").unwrap();
+    assert_eq!((start, prompt, question.as_str(), options.temperature), (10, vec![1, 2], "q1", 0.5));
+    assert!(conv.regenerate("3").is_none());
+    conv.withdraw("1");
+    conv.push("1".into(), question, "b1".into(), options, 2, vec![1, 2, 6, 7]);
+    assert_eq!(conv.answers("1"), ["a1", "b1"]);
+    let path = conv.active_path();
+    assert_eq!((path.len(), path[0].alternative, path[0].alternatives), (1, 1, 2));
+
+    // the first answer is still there, with the turn that followed it
+    let (keep, prefill) = conv.select_answer("1", 0).unwrap();
+    assert_eq!((keep, prefill), (10, vec![1, 2, 3, 4, 5]));
+    assert_eq!(conv.active_path()[1].answer, "a2");
+    assert!(conv.select_answer("1", 2).is_none());
+}
+
+#[test]
+fn test_export_import() {
+    let mut conv = Conversation::new(10);
+    conv.push("1".into(), "q1".into(), "a1".into(), GenerateOptions::default(), 1, vec![1, 2]);
+    conv.push("2".into(), "q2".into(), "a2".into(), GenerateOptions::default(), 1, vec![3, 4]);
+    conv.withdraw("2");
+    conv.push("3".into(), "q3".into(), "a3".into(), GenerateOptions::default(), 1, vec![5, 6]);
+    let turns = conv.export();
+    assert_eq!(turns.iter().map(|t| t.active).collect::<Vec<_>>(), [true, false, true]);
+    assert_eq!(turns[2].parent, Some(0));
+
+    let tokenize = |t: &ExportedTurn| (vec![t.id.parse().unwrap()], vec![0]);
+    let (imported, prefill) = Conversation::import(10, &turns, tokenize).unwrap();
+    assert_eq!(prefill, [1, 0, 3, 0]);
+    let ids: Vec<String> = imported.active_path().into_iter().map(|turn| turn.id).collect();
+    assert_eq!(ids, ["1", "3"]);
+    assert_eq!(imported.turns().len(), 3);
+
+    let export = ExportedConversation {
+        name: "对话1".into(),
+        model: "chat".into(),
+        exported_at: 1729339200,
+        options: GenerateOptions::default(),
+        turns: turns.clone(),
+    };
+    let markdown = export.to_markdown();
+    assert!(markdown.starts_with("# 对话1\n\n> 模型: chat · 导出时间: 2024-10-19 12:00:00 UTC\n"));
+    assert!(markdown.contains("q1") && markdown.contains("a3") && !markdown.contains("q2"));
+
+    let mut bad = turns.clone();
+    bad[0].parent = Some(2);
+    assert!(Conversation::import(10, &bad, tokenize).is_err());
+}
+
+#[test]
+fn test_fork() {
+    let mut conv = Conversation::new(10);
+    conv.push("1".into(), "q1".into(), "a1".into(), GenerateOptions::default(), 1, vec![1, 2]);
+    conv.push("2".into(), "q2".into(), "a2".into(), GenerateOptions::default(), 1, vec![3, 4, 5]);
+    conv.push("3".into(), "q3".into(), "a3".into(), GenerateOptions::default(), 1, vec![6]);
+    let (fork, cache_len) = conv.fork("2").unwrap();
+    assert_eq!(cache_len, 15);
+    assert_eq!(fork.active_tokens(cache_len), [1, 2, 3, 4, 5]);
+    assert_eq!(conv.active_tokens(13), [1, 2, 3]);
+    let ids: Vec<String> = fork.active_path().into_iter().map(|turn| turn.id).collect();
+    assert_eq!(ids, ["1", "2"]);
+    assert_eq!((fork.len(), conv.len()), (2, 3));
+    assert!(conv.fork("4").is_none());
+}
@@ -1,56 +0,0 @@
-use std::{clone, usize, vec};
-
-use crate::tensor::Tensor;
-#[derive(Clone)]
-pub struct KVCache<T> {
-    k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    #[allow(unused)]
-    max_seq_len: usize,
-    dim: usize,
-    length: usize, // length of the current sequence
-}
-
-impl<T: Default + Copy> KVCache<T> {
-    pub fn new(n_layers: usize, max_seq_len: usize, dim: usize, init_len: usize) -> Self {
-        KVCache {
-            k_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
-                .collect(),
-            v_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
-                .collect(),
-            max_seq_len: max_seq_len,
-            dim: dim,
-            length: init_len,
-        }
-    }
-
-    pub fn k_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.k_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
-    }
-
-    pub fn v_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.v_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
-    }
-
-    pub fn increment(&mut self, seq_len: usize) {
-        self.length += seq_len;
-    }
-
-    pub fn len(&self) -> usize {
-        self.length
-    }
-
-    pub fn reset_len(&mut self, new_len: usize) {
-        self.length = new_len;
-        // for layer in 0..self.k_cache.len() {
-        //     self.k_cache[layer].shrink(new_len);   
-        // }
-
-        // for layer in 0..self.k_cache.len() {
-        //     self.k_cache[layer].shrink(new_len);   
-        // }
-    } 
-}
-
@@ -1,26 +1,28 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod config;
-mod kvcache;
-mod model;
-mod operators;
-mod params;
-mod tensor;
+mod conversation;
+mod search;
+mod server;
+use chat_core::generate::{self, GenerateOptions};
+use chat_core::tensor::Tensor;
+use chat_core::{eval, grammar, kvcache, model, template, vocab};
+use conversation::{Conversation, ExportedConversation, ExportedTurn, TurnInfo};
 use kvcache::KVCache;
 use rand::Rng;
 
 use core::fmt;
 use std::{alloc::System, path::PathBuf};
-use model::Llama;
+use model::Model;
 use rand::random;
 use tokenizers::Tokenizer;
 
 use std::time::Duration;
 use std::thread;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 #[tauri::command]
@@ -33,32 +35,100 @@ struct Pair<T, U> {
   second: U,
 }
 
+// 单个 token 的对数概率
+#[derive(serde::Serialize, Clone)]
+struct TopToken {
+  id: u32,
+  token: String,
+  logprob: f32,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct TokenInfo {
+  id: u32,
+  token: String,
+  logprob: f32,
+  top: Vec<TopToken>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Task {
+  Ask,        // 回答新问题
+  Regenerate, // 重新生成问题 id 的答案, text 不用
+  Withdraw,   // 撤销问题 id 及其后的对话, text 不用
+}
+
+#[derive(Clone)]
+struct Question {
+  id: String,
+  text: String,
+  options: Option<GenerateOptions>,
+  task: Task,
+}
+
+// 一个对话待处理的任务, 由一个线程按提交顺序依次处理
+#[derive(Default)]
+struct QuestionQueue {
+  questions: VecDeque<Question>,
+  running: bool,
+}
+
+// 候选答案及其累计对数概率
+#[derive(serde::Serialize, Clone)]
+struct Alternative {
+  text: String,
+  logprob: f32,
+}
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum AnswerState {
+  Queued,
+  Running,
+  Done,
+  Failed,
+  Cancelled,
+}
+
+// 一个问题的答案及其处理状态
+#[derive(serde::Serialize, Clone)]
+struct AnswerStatus {
+  id: String,
+  state: AnswerState,
+  answer: String,
+  error: Option<String>,
+  alternatives: Vec<Alternative>, // beam search / 多次采样得到的全部候选答案
+  logprobs: Vec<TokenInfo>,       // 提问时设置 logprobs 才有
+  #[serde(skip)]
+  delivered: bool, // 已经由 send_answer 返回过
+}
+
+// 一个对话: 对话树及当前分支对应的 KVCache
+type Session = Pair<Conversation, KVCache<f32>>;
 
 lazy_static! {
-  static ref QUESTION_MAP: Arc<Mutex<HashMap<String, Pair<String,String>>>> = {
+  static ref QUESTION_MAP: Arc<Mutex<HashMap<String, QuestionQueue>>> = {
     Arc::new(Mutex::new(HashMap::new()))
   };
-  static ref ANSWER_MAP: Arc<Mutex<HashMap<String, String>>> = {
+  // 每个对话中各问题的答案, 按提问顺序
+  static ref ANSWER_MAP: Arc<Mutex<HashMap<String, Vec<AnswerStatus>>>> = {
     Arc::new(Mutex::new(HashMap::new()))
   };
 
-  static ref RESET_MAP: Arc<Mutex<HashMap<String, String>>> = {
-    Arc::new(Mutex::new(HashMap::new()))
-  };
-
-  static ref CACHE_MAP: Arc<Mutex<HashMap<String, Pair<Vec<Pair<String, usize>>, kvcache::KVCache<f32>>>>> = {
+  // 每个对话的对话树及当前分支对应的 KVCache
+  static ref CACHE_MAP: Arc<Mutex<HashMap<String, Session>>> = {
     Arc::new(Mutex::new(HashMap::new()))
   };
 }
 
 lazy_static! {
-  static ref LLAMACOM: Arc< model::Llama::<f32>> = {
+  static ref LLAMACOM: Arc<Model> = {
     println!("start!");
     let project_dir = env!("CARGO_MANIFEST_DIR");
     println!("project_dir: {project_dir}");
     let model_dir = PathBuf::from(project_dir).join("models").join("chat");
-    println!("load Llama");
-    Arc::new(model::Llama::<f32>::from_safetensors(&model_dir))
+    println!("load model");
+    Arc::new(Model::from_safetensors(&model_dir))
   };
 
   static ref TOKENIZER: Arc<Tokenizer> = {
@@ -66,143 +136,681 @@ lazy_static! {
     let model_dir = PathBuf::from(project_dir).join("models").join("chat");
     Arc::new(Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap())
   };
+
+  // 每个新对话开始时的 cache: 已填入对话模板的开场白
+  static ref PREAMBLE_CACHE: KVCache<f32> = {
+    let mut cache = LLAMACOM.new_cache();
+    let binding = TOKENIZER.encode(template::PREAMBLE, true).unwrap();
+    LLAMACOM.chat_generate(binding.get_ids(), &mut cache, 500, 0.9, 4, 1.);
+    cache
+  };
+
+  // 约束生成用的词表前缀树
+  static ref VOCAB_TRIE: Arc<grammar::TokenTrie> = {
+    Arc::new(grammar::TokenTrie::new(vocab::token_bytes(&TOKENIZER)))
+  };
+
+  // 逐 token 解码生成的答案, 多字节的汉字凑齐后才输出
+  static ref TOKEN_DECODER: Arc<vocab::TokenDecoder> = {
+    Arc::new(vocab::TokenDecoder::new(&TOKENIZER))
+  };
 }
 
 // 接受参数
 #[tauri::command(rename_all = "snake_case")]
-fn deal_question(question: &str, name: &str, id: String) -> String {
+fn deal_question(question: &str, name: &str, id: String, options: Option<GenerateOptions>) -> String {
   println!("前端传过来的问题: {}, {}, {}", question, name, id);
-  let mut s: std::sync::MutexGuard<'_, HashMap<String, Pair<String, String>>> = QUESTION_MAP.lock().unwrap();
-  s.insert(name.into(), Pair { first: id.clone(), second: question.into() });
+  enqueue(name, Question { id, text: question.into(), options, task: Task::Ask });
+  "".into()
+}
+
+// 重新生成问题 id 的答案: 回到该问题之后重新采样, 不传 options 时沿用原来的生成参数.
+// 新答案同样通过 send_answer 返回, 之前的答案可通过 list_answers / select_answer 查看和切换
+#[tauri::command(rename_all = "snake_case")]
+fn regenerate_answer(name: &str, id: String, options: Option<GenerateOptions>) -> String {
+  println!("重新生成答案: {}, {}", name, id);
+  enqueue(name, Question { id, text: String::new(), options, task: Task::Regenerate });
   "".into()
 }
 
+fn enqueue(name: &str, question: Question) {
+  let mut question_map = lock(&QUESTION_MAP);
+  if question.task != Task::Withdraw {
+    set_answer(name, &question.id, |status| *status = AnswerStatus::new(&question.id));
+  }
+  question_map.entry(name.into()).or_default().questions.push_back(question);
+}
+
+impl AnswerStatus {
+  fn new(id: &str) -> Self {
+    AnswerStatus {
+      id: id.into(),
+      state: AnswerState::Queued,
+      answer: String::new(),
+      error: None,
+      alternatives: Vec::new(),
+      logprobs: Vec::new(),
+      delivered: false,
+    }
+  }
+
+  fn finished(&self) -> bool {
+    matches!(self.state, AnswerState::Done | AnswerState::Failed | AnswerState::Cancelled)
+  }
+}
+
+// 加锁. 某个对话的处理线程持锁时 panic 不影响其他命令继续使用
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// 更新对话 name 中问题 id 的答案状态, 没有时新建
+fn set_answer(name: &str, id: &str, update: impl FnOnce(&mut AnswerStatus)) {
+  let mut answer_map = lock(&ANSWER_MAP);
+  let answers = answer_map.entry(name.into()).or_default();
+  let index = match answers.iter().position(|status| status.id == id) {
+    Some(index) => index,
+    None => {
+      answers.push(AnswerStatus::new(id));
+      answers.len() - 1
+    }
+  };
+  update(&mut answers[index]);
+}
+
+// 查询问题的答案及状态 (queued / running / done / failed / cancelled)
+#[tauri::command(rename_all = "snake_case")]
+fn question_status(name: String, id: String) -> Option<AnswerStatus> {
+  let answer_map = lock(&ANSWER_MAP);
+  answer_map.get(&name)?.iter().find(|status| status.id == id).cloned()
+}
+
+// 对话中所有问题的答案及状态, 按提问顺序
+#[tauri::command(rename_all = "snake_case")]
+fn list_question_status(name: String) -> Vec<AnswerStatus> {
+  let answer_map = lock(&ANSWER_MAP);
+  answer_map.get(&name).cloned().unwrap_or_default()
+}
+
 // 删除
 #[tauri::command(rename_all = "snake_case")]
 fn reset_question( name: String, id: String) -> String {
   println!("重新设置的传过来的问题: {}, {}", name, id);
 
-  let mut q = RESET_MAP.lock().unwrap();
-  q.insert(name, id);
+  // 还在排队的提问直接取消, 否则 (已回答, 或只是在排队重新生成) 排在该对话已有的任务之后撤销
+  let mut question_map = lock(&QUESTION_MAP);
+  let queue = question_map.entry(name.clone()).or_default();
+  let queued = queue.questions.iter().position(|q| q.id == id && q.task == Task::Ask);
+  if let Some(index) = queued {
+    queue.questions.remove(index);
+    set_answer(&name, &id, |status| status.state = AnswerState::Cancelled);
+  } else {
+    queue.questions.push_back(Question { id, text: String::new(), options: None, task: Task::Withdraw });
+  }
   "".into()
 }
 
-// 返回答案
+// 返回答案: 传 id 时返回该问题的答案, 否则返回最早完成且未返回过的答案; 还没有处理完时返回 None.
+// 失败时返回错误信息, 取消时返回空串
+#[tauri::command(rename_all = "snake_case")]
+fn send_answer(name: String, id: Option<String>) -> Option<String> {
+  let mut answer_map = lock(&ANSWER_MAP);
+  let answers = answer_map.get_mut(&name)?;
+  let status = match &id {
+    Some(id) => answers.iter_mut().find(|status| &status.id == id && status.finished()),
+    None => answers.iter_mut().find(|status| status.finished() && !status.delivered),
+  };
+  match status {
+    Some(status) => {
+      status.delivered = true;
+      println!("{}  answer is {}", name, status.answer);
+      match status.state {
+        AnswerState::Failed => Some(status.error.clone().unwrap_or_default()),
+        AnswerState::Cancelled => Some(String::new()),
+        _ => Some(status.answer.clone()),
+      }
+    },
+    None => None,
+  }
+}
+
+// 返回 beam search / 多次采样得到的全部候选答案; 不传 id 时为最近返回的答案
+#[tauri::command(rename_all = "snake_case")]
+fn send_alternatives(name: String, id: Option<String>) -> Vec<Alternative> {
+  delivered_status(&name, id).map(|status| status.alternatives).unwrap_or_default()
+}
+
+// 返回答案中每个 token 的对数概率, 需要提问时设置 logprobs; 不传 id 时为最近返回的答案
+#[tauri::command(rename_all = "snake_case")]
+fn send_logprobs(name: String, id: Option<String>) -> Vec<TokenInfo> {
+  delivered_status(&name, id).map(|status| status.logprobs).unwrap_or_default()
+}
+
+fn delivered_status(name: &str, id: Option<String>) -> Option<AnswerStatus> {
+  let answer_map = lock(&ANSWER_MAP);
+  let answers = answer_map.get(name)?;
+  match id {
+    Some(id) => answers.iter().find(|status| status.id == id).cloned(),
+    None => answers.iter().rev().find(|status| status.delivered).cloned(),
+  }
+}
+
+// 文本向量: 每段文本返回一个向量 (mean / last_token), per_token 时每个 token 一个向量.
+// 计算较慢, 用 async 命令避免阻塞界面线程
+#[tauri::command(rename_all = "snake_case")]
+async fn embed_texts(texts: Vec<String>, pooling: Option<model::Pooling>) -> Vec<Vec<Vec<f32>>> {
+  let pooling = pooling.unwrap_or(model::Pooling::Mean);
+  texts
+    .iter()
+    .map(|text| {
+      let binding = TOKENIZER.encode(text.as_str(), true).unwrap();
+      LLAMACOM.embed(binding.get_ids(), pooling)
+    })
+    .collect()
+}
+
+// 提示词中每个 token (第一个除外) 在前文条件下的对数概率及 top_logprobs 个备选 token
 #[tauri::command(rename_all = "snake_case")]
-fn send_answer(name: String) -> String {
-  //let fake_answer = vec!["abcd", "1234"];
-  let mut answer_map =  ANSWER_MAP.lock().unwrap();
- // let tmp = answer_map.get(&name);
-  println!("answer map size:{}", answer_map.len());
-  match answer_map.remove(&name) {
-      Some(s) => {
-        println!("{}  answer is {}", name, s);
-        s.clone()
-      },
-      // None =>  fake_answer[rand::thread_rng().gen_range(0..fake_answer.len())].into(),
-       None =>  "".into(),
+async fn prompt_logprobs(text: String, top_logprobs: Option<usize>) -> Vec<TokenInfo> {
+  let binding = TOKENIZER.encode(text.as_str(), true).unwrap();
+  let steps = eval::prompt_logprobs(&LLAMACOM, binding.get_ids(), top_logprobs.unwrap_or(0));
+  steps.iter().map(token_info).collect()
+}
+
+fn token_text(id: u32) -> String {
+  TOKENIZER.decode(&[id], false).unwrap()
+}
+
+fn token_info(step: &model::TokenLogprob) -> TokenInfo {
+  TokenInfo {
+    id: step.token,
+    token: token_text(step.token),
+    logprob: step.logprob,
+    top: step.top.iter().map(|&(id, logprob)| TopToken { id, token: token_text(id), logprob }).collect(),
   }
-  // s
 }
 
-fn infer(name: String, input: String, id: String) {
+fn infer(name: String, question: Question) {
   println!("{name}, into infer");
-  let mut cache_map = CACHE_MAP.lock().unwrap();
-  let mut vec_cache = &mut cache_map.get_mut(&name).unwrap();
-  let mut kvcache = &mut vec_cache.second;
-  vec_cache.first.push(Pair { first: id.clone(), second: kvcache.len() });
-  let new_input = format!("<|im_start|>user\n{}\n<|im_end|>\n<|im_start|>assistant", input.trim());
-  let binding = TOKENIZER.encode(new_input, true).unwrap();
-  let input_ids = binding.get_ids();
+  let id = question.id;
+  let mut cache_map = lock(&CACHE_MAP);
+  // 对话可能在排队期间被删除或改名
+  let Some(vec_cache) = cache_map.get_mut(&name) else {
+    set_answer(&name, &id, |status| status.state = AnswerState::Cancelled);
+    return;
+  };
+  let old_len = vec_cache.second.len();
+  let (start_len, input, input_ids, options) = if question.task == Task::Regenerate {
+    // 回到该问题的用户输入之后, 只需重新输入它的最后一个 token
+    let Some((start, prompt, input, options)) = vec_cache.first.regenerate(&id) else {
+      println!("{name}, no question {id} to regenerate");
+      set_answer(&name, &id, |status| fail(status, format!("no question {id} to regenerate")));
+      return;
+    };
+    rewind(vec_cache, start + prompt.len() - 1);
+    (start, input, prompt, question.options.unwrap_or(options))
+  } else {
+    let binding = TOKENIZER.encode(template::user_turn(&question.text), true).unwrap();
+    let options = question.options.unwrap_or_else(|| vec_cache.first.options.clone());
+    (vec_cache.second.len(), question.text, binding.get_ids().to_vec(), options)
+  };
+  let kvcache = &mut vec_cache.second;
+  let skip = kvcache.len() - start_len;
+  // 剩余的 cache 放不下问题或一个 token 的答案时直接失败, 答案长度不超过剩余的位置
+  let room = LLAMACOM.room(kvcache, input_ids[skip..].len());
+  if room == 0 {
+    println!("{name}, no room for question {id}");
+    set_answer(&name, &id, |status| {
+      fail(status, format!("conversation is too long for the model ({} tokens)", start_len + input_ids.len()))
+    });
+    rewind(vec_cache, old_len);
+    return;
+  }
+  let mut limited = options.clone();
+  limited.max_len = limited.max_len.min(room);
   println!("{name}, start infer answer");
-  let output_ids = LLAMACOM.chat_generate(input_ids, kvcache, 500, 0.9, 4, 1.);
-  let answer = TOKENIZER.decode(&output_ids, true).unwrap();
-  let mut answer_map = ANSWER_MAP.lock().unwrap();
-  answer_map.insert(name.clone(), answer);
-  println!("infer {name}:question :{}  len: {}", id, kvcache.len()); 
+  // 普通采样时边生成边更新答案, 生成过程中 question_status 即可看到已生成的部分
+  let mut detokenizer = TOKEN_DECODER.detokenizer();
+  let mut partial = String::new();
+  let generation = panic::catch_unwind(AssertUnwindSafe(|| {
+    generate::generate(&LLAMACOM, &input_ids[skip..], kvcache, None, &limited, || &VOCAB_TRIE, |token| {
+      let text = detokenizer.push(token);
+      if !text.is_empty() {
+        partial.push_str(&text);
+        set_answer(&name, &id, |status| status.answer = partial.clone());
+      }
+      true
+    })
+  }));
+  let mut logprobs = Vec::new();
+  let candidates = match generation {
+    Ok(Ok(generation)) => {
+      if let Some(steps) = generation.logprobs {
+        logprobs = steps.iter().map(token_info).collect();
+      }
+      generation.candidates
+    }
+    Ok(Err(e)) => {
+      println!("{name}, grammar error: {e}");
+      set_answer(&name, &id, |status| fail(status, format!("grammar error: {e}")));
+      restore(vec_cache, old_len, question.task);
+      return;
+    }
+    // 生成中途 panic 时 cache 可能只写了一半, 回到提问之前
+    Err(payload) => {
+      let message = panic_message(&payload);
+      println!("{name}, generation panicked: {message}");
+      set_answer(&name, &id, |status| fail(status, format!("generation failed: {message}")));
+      restore(vec_cache, old_len, question.task);
+      return;
+    }
+  };
+  let alternatives: Vec<Alternative> = candidates
+    .iter()
+    .map(|(ids, logprob)| Alternative { text: TOKEN_DECODER.decode(ids), logprob: *logprob })
+    .collect();
+  let answer = alternatives[0].text.clone();
+  // 记录这一轮写入 cache 的全部 token, 切换分支时用来重新 prefill
+  let fed = kvcache.len() - start_len;
+  let tokens = input_ids.iter().chain(&candidates[0].0).take(fed).copied().collect();
+  if question.task == Task::Regenerate {
+    // 原来的答案及其后的对话保留为一个分支
+    vec_cache.first.withdraw(&id);
+  }
+  vec_cache.first.push(id.clone(), input, answer.clone(), options, input_ids.len(), tokens);
+  set_answer(&name, &id, |status| {
+    status.state = AnswerState::Done;
+    status.answer = answer;
+    if alternatives.len() > 1 {
+      status.alternatives = alternatives;
+    }
+    status.logprobs = logprobs;
+  });
+  println!("infer {name}:question :{}  len: {}", id, vec_cache.second.len()); 
 }
 
-fn reset_cache(name: String, id: String) {
-  let mut cache_map = CACHE_MAP.lock().unwrap();
-  let mut vec_cache = &mut cache_map.get_mut(&name).unwrap();
-  println!("{name}, start reset");
-  let mut index = 0;
-  while vec_cache.first.is_empty() == false && index == 0 {
-      match  vec_cache.first.pop() {
-        Some(tmp) => { 
-          if tmp.first == id  {
-            index = tmp.second;
-            break;
-          }
+fn fail(status: &mut AnswerStatus, error: String) {
+  status.state = AnswerState::Failed;
+  status.error = Some(error);
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+  match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+    (Some(message), _) => message.to_string(),
+    (_, Some(message)) => message.clone(),
+    _ => "unknown panic".into(),
+  }
+}
+
+// 按提交顺序处理对话 name 的任务, 队列为空时退出
+fn work(name: String) {
+  loop {
+    let question = {
+      let mut question_map = lock(&QUESTION_MAP);
+      let Some(queue) = question_map.get_mut(&name) else { return };
+      match queue.questions.pop_front() {
+        Some(question) => question,
+        None => {
+          queue.running = false;
+          return;
         }
-        None => break,
       }
+    };
+    // infer 自己处理生成中的 panic, 这里兜底其余的, 保证答案结束且线程继续处理后面的任务
+    let id = question.id.clone();
+    let task = question.task;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match task {
+      Task::Withdraw => reset_cache(name.clone(), question.id),
+      _ => {
+        set_answer(&name, &id, |status| status.state = AnswerState::Running);
+        infer(name.clone(), question);
+      }
+    }));
+    if let Err(payload) = result {
+      let message = panic_message(&payload);
+      println!("{name}, task {id} panicked: {message}");
+      if task != Task::Withdraw {
+        set_answer(&name, &id, |status| fail(status, format!("generation failed: {message}")));
+      }
+      // 不知道 panic 时 cache 写到了哪里, 按对话树重建当前分支
+      if let Some(session) = lock(&CACHE_MAP).get_mut(&name) {
+        rebuild(session, usize::MAX);
+      }
+    }
+  }
+}
+
+fn reset_cache(name: String, id: String) {
+  let mut cache_map = lock(&CACHE_MAP);
+  let Some(vec_cache) = cache_map.get_mut(&name) else { return };
+  println!("{name}, start reset");
+  // 撤销的问题及其后的对话保留为一个分支, 当前分支回到它之前
+  if let Some(index) = vec_cache.first.withdraw(&id) {
+    print!("reset: {}, len to {}", name, index);
+    rewind(vec_cache, index);
+  }
+}
+
+// 把 cache 截断到 len. 滑动窗口模型的环形 cache 可能已覆盖 len 之前需要的部分, 这时从开场白起重新 prefill 当前分支
+fn rewind(session: &mut Session, len: usize) {
+  if session.second.retains(len) {
+    session.second.reset_len(len);
+  } else {
+    rebuild(session, len);
+  }
+}
+
+// 生成失败后回到提问前的长度 len. 重新生成时新答案已覆盖了原答案在 cache 中的位置, 截断不能恢复, 要重新 prefill
+fn restore(session: &mut Session, len: usize, task: Task) {
+  match task {
+    Task::Regenerate => rebuild(session, len),
+    _ => rewind(session, len),
+  }
+}
+
+// 从开场白起重新 prefill 当前分支的前 len 个位置
+fn rebuild(session: &mut Session, len: usize) {
+  let tokens = session.first.active_tokens(len);
+  println!("rebuild cache: {} tokens", tokens.len());
+  session.second = PREAMBLE_CACHE.fork();
+  if !tokens.is_empty() {
+    LLAMACOM.forward(&Tensor::<u32>::new(tokens.clone(), &[tokens.len()]), &mut session.second);
+  }
+}
+
+// 在所有对话的问答中全文搜索, 按相关度 (BM25) 排序, 返回对话名/问题 id 及片段
+#[tauri::command(rename_all = "snake_case")]
+async fn search_history(query: String, limit: Option<usize>) -> Vec<search::SearchHit> {
+  let cache_map = lock(&CACHE_MAP);
+  let documents: Vec<search::Document> = cache_map
+    .iter()
+    .flat_map(|(name, session)| {
+      session.first.turns().into_iter().map(|turn| search::Document {
+        session: name.clone(),
+        id: turn.id,
+        alternative: turn.alternative,
+        question: turn.question,
+        answer: turn.answer,
+      })
+    })
+    .collect();
+  drop(cache_map);
+  search::search(&documents, &query, limit.unwrap_or(20))
+}
+
+// 对话的元信息
+#[derive(serde::Serialize)]
+struct SessionInfo {
+  name: String,
+  created: u64,
+  updated: u64,
+  turns: usize,     // 全部分支的问答数
+  cache_len: usize, // 当前分支占用的 KVCache 长度
+  options: GenerateOptions,
+}
+
+// 新建对话, options 为该对话提问时的默认生成参数
+#[tauri::command(rename_all = "snake_case")]
+async fn create_session(name: String, options: Option<GenerateOptions>) -> Result<(), String> {
+  let mut conversation = Conversation::new(PREAMBLE_CACHE.len());
+  conversation.options = options.unwrap_or_default();
+  let cache = PREAMBLE_CACHE.fork();
+  let mut cache_map = lock(&CACHE_MAP);
+  if cache_map.contains_key(&name) {
+    return Err(format!("session {name} already exists"));
   }
-  print!("reset: {}, len to {}", name, index);
-  vec_cache.second.reset_len(index);
+  cache_map.insert(name, Pair { first: conversation, second: cache });
+  Ok(())
+}
 
+#[tauri::command(rename_all = "snake_case")]
+async fn list_sessions() -> Vec<SessionInfo> {
+  let cache_map = lock(&CACHE_MAP);
+  let mut sessions: Vec<SessionInfo> = cache_map
+    .iter()
+    .map(|(name, session)| SessionInfo {
+      name: name.clone(),
+      created: session.first.created,
+      updated: session.first.updated(),
+      turns: session.first.len(),
+      cache_len: session.second.len(),
+      options: session.first.options.clone(),
+    })
+    .collect();
+  sessions.sort_by_key(|session| session.created);
+  sessions
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn rename_session(name: String, new_name: String) -> Result<(), String> {
+  let mut cache_map = lock(&CACHE_MAP);
+  if cache_map.contains_key(&new_name) {
+    return Err(format!("session {new_name} already exists"));
+  }
+  let session = cache_map.remove(&name).ok_or(format!("no session named {name}"))?;
+  cache_map.insert(new_name.clone(), session);
+  move_pending(&name, Some(&new_name));
+  Ok(())
+}
+
+// 删除对话并释放其 KVCache, 同时丢弃还未处理的提问和未取走的答案
+#[tauri::command(rename_all = "snake_case")]
+async fn delete_session(name: String) -> bool {
+  let mut cache_map = lock(&CACHE_MAP);
+  move_pending(&name, None);
+  cache_map.remove(&name).is_some()
+}
+
+// 复制对话: 传 id 时只保留当前分支到该问题为止的对话, 否则复制全部分支
+#[tauri::command(rename_all = "snake_case")]
+async fn fork_session(name: String, new_name: String, id: Option<String>) -> Result<(), String> {
+  let mut cache_map = lock(&CACHE_MAP);
+  if cache_map.contains_key(&new_name) {
+    return Err(format!("session {new_name} already exists"));
+  }
+  let session = cache_map.get(&name).ok_or(format!("no session named {name}"))?;
+  let (conversation, cache_len) = match id {
+    Some(id) => session.first.fork(&id).ok_or(format!("no question {id} on the current branch of {name}"))?,
+    None => (session.first.clone(), session.second.len()),
+  };
+  let mut fork = Pair { first: conversation, second: session.second.fork() };
+  rewind(&mut fork, cache_len);
+  cache_map.insert(new_name, fork);
+  Ok(())
+}
+
+// 把对话 from 未处理的提问/撤销和未取走的答案转给 to, to 为 None 时丢弃. 调用方需持有 CACHE_MAP 锁
+fn move_pending(from: &str, to: Option<&str>) {
+  fn rekey<T>(map: &Mutex<HashMap<String, T>>, from: &str, to: Option<&str>) {
+    let mut map = lock(map);
+    if let (Some(value), Some(to)) = (map.remove(from), to) {
+      map.insert(to.into(), value);
+    }
+  }
+  rekey(&QUESTION_MAP, from, to);
+  rekey(&ANSWER_MAP, from, to);
+  // 原来的处理线程找不到改名前的队列后会退出, 由 accept 为新名字重新启动
+  if let Some(to) = to {
+    if let Some(queue) = lock(&QUESTION_MAP).get_mut(to) {
+      queue.running = false;
+    }
+  }
+}
+
+// 导出对话: format 为 "json" (默认, 含全部分支, 可再导入) 或 "markdown" (当前分支)
+#[tauri::command(rename_all = "snake_case")]
+async fn export_session(name: String, format: Option<String>) -> Result<String, String> {
+  let cache_map = lock(&CACHE_MAP);
+  let session = cache_map.get(&name).ok_or(format!("no session named {name}"))?;
+  let export = ExportedConversation {
+    name: name.clone(),
+    model: server::MODEL_ID.into(),
+    exported_at: conversation::now(),
+    options: session.first.options.clone(),
+    turns: session.first.export(),
+  };
+  match format.as_deref().unwrap_or("json") {
+    "json" => Ok(serde_json::to_string_pretty(&export).unwrap()),
+    "markdown" => Ok(export.to_markdown()),
+    other => Err(format!("unknown export format {other}")),
+  }
+}
+
+// 导入 JSON 格式的对话, 重建对话树并 prefill 当前分支的 KVCache. 返回对话名, 不传 name 时用导出时的名字
+#[tauri::command(rename_all = "snake_case")]
+async fn import_session(data: String, name: Option<String>) -> Result<String, String> {
+  let export: ExportedConversation = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+  let name = name.unwrap_or(export.name);
+  let tokenize = |turn: &ExportedTurn| {
+    let prompt = TOKENIZER.encode(template::user_turn(&turn.question), true).unwrap();
+    let answer = TOKENIZER.encode(turn.answer.as_str(), false).unwrap();
+    (prompt.get_ids().to_vec(), answer.get_ids().to_vec())
+  };
+  let (mut conversation, prefill) = Conversation::import(PREAMBLE_CACHE.len(), &export.turns, tokenize)?;
+  conversation.options = export.options;
+  if PREAMBLE_CACHE.len() + prefill.len() > LLAMACOM.max_seq_len() {
+    return Err(format!("conversation is too long for the model ({} tokens)", PREAMBLE_CACHE.len() + prefill.len()));
+  }
+  let mut cache = PREAMBLE_CACHE.fork();
+  if !prefill.is_empty() {
+    LLAMACOM.forward(&Tensor::<u32>::new(prefill.clone(), &[prefill.len()]), &mut cache);
+  }
+  let mut cache_map = lock(&CACHE_MAP);
+  if cache_map.contains_key(&name) {
+    return Err(format!("session {name} already exists"));
+  }
+  println!("import {name}: {} turns, cache len {}", export.turns.len(), cache.len());
+  cache_map.insert(name.clone(), Pair { first: conversation, second: cache });
+  Ok(name)
+}
+
+// 列出对话树中所有分支的全部问答, active 标记当前分支
+#[tauri::command(rename_all = "snake_case")]
+async fn list_branches(name: String) -> Vec<TurnInfo> {
+  let cache_map = lock(&CACHE_MAP);
+  cache_map.get(&name).map(|session| session.first.turns()).unwrap_or_default()
+}
+
+// 切换到经过问题 id 的分支, 重新 prefill 与当前分支不同的部分, 返回切换后的当前分支
+#[tauri::command(rename_all = "snake_case")]
+async fn switch_branch(name: String, id: String) -> Vec<TurnInfo> {
+  let mut cache_map = lock(&CACHE_MAP);
+  let Some(session) = cache_map.get_mut(&name) else { return Vec::new() };
+  println!("{name}, switch to {id}");
+  let switch = session.first.switch(&id);
+  apply_switch(session, switch);
+  session.first.active_path()
+}
+
+// 问题 id 的全部答案, 按生成顺序
+#[tauri::command(rename_all = "snake_case")]
+async fn list_answers(name: String, id: String) -> Vec<String> {
+  let cache_map = lock(&CACHE_MAP);
+  cache_map.get(&name).map(|session| session.first.answers(&id)).unwrap_or_default()
+}
+
+// 选用问题 id 的第 index 个答案 (及其后的对话), 返回切换后的当前分支
+#[tauri::command(rename_all = "snake_case")]
+async fn select_answer(name: String, id: String, index: usize) -> Vec<TurnInfo> {
+  let mut cache_map = lock(&CACHE_MAP);
+  let Some(session) = cache_map.get_mut(&name) else { return Vec::new() };
+  let switch = session.first.select_answer(&id, index);
+  apply_switch(session, switch);
+  session.first.active_path()
+}
+
+// 按 Conversation::switch 的结果截断 cache 并 prefill 新分支
+fn apply_switch(session: &mut Session, switch: Option<(usize, Vec<u32>)>) {
+  if let Some((keep_len, prefill)) = switch {
+    rewind(session, keep_len);
+    if !prefill.is_empty() {
+      LLAMACOM.forward(&Tensor::<u32>::new(prefill.clone(), &[prefill.len()]), &mut session.second);
+    }
+  }
 }
 
 fn accept() {
   println!("tokenizer!");
-  let  input = "<|im_start|>system
-  You are a highly knowledgeable and friendly assistant. Your goal is to understand and respond to user inquiries with clarity. Your interactions are always respectful, helpful, and focused on delivering the most accurate information to the user.<|im_end|>
-<|im_start|>user
-  Hey! Got a question for you!<|im_end|>
-<|im_start|>assistant";
-  let mut tmp_kvcahce = LLAMACOM.new_cache();
-  let binding = TOKENIZER.encode(input, true).unwrap();
-  let input_ids = binding.get_ids();
-  LLAMACOM.chat_generate(input_ids, &mut tmp_kvcahce, 500, 0.9, 4, 1.);
+  let tmp_kvcahce = &*PREAMBLE_CACHE;
   loop {
     thread::sleep(Duration::from_secs(3));
-    let mut cache_map = CACHE_MAP.lock().unwrap();
-    let mut question_map = QUESTION_MAP.lock().unwrap();
-    let mut reset_map = RESET_MAP.lock().unwrap();
-    let reset_key: Vec<String> = reset_map
-    .iter()
-    .map(
-      |(name, id)| {
-        let name_clone = name.clone();
-        let id_clone = id.clone();
-        thread::spawn(move || reset_cache(name_clone, id_clone));
-        name.clone()
+    let mut cache_map = lock(&CACHE_MAP);
+    let mut question_map = lock(&QUESTION_MAP);
+    for (name, queue) in question_map.iter_mut() {
+      if queue.running || queue.questions.is_empty() {
+        continue;
       }
-    ).collect();
-
-    for key in reset_key {
-      reset_map.remove(&key);
-    }
-
-    let keys_to_remove: Vec<String> = question_map
-
-        .iter()  
-        .map(|(k, _value)| {
-            if !cache_map.contains_key(k) {
-              cache_map.insert(k.clone(), Pair { first: Vec::new(), second: tmp_kvcahce.clone() });
-            } 
-            let t = k.clone();
-            let id= _value.first.clone();
-            let v = _value.second.clone();
-            thread::spawn(move || infer(t, v, id));
-          k.clone()
-        })
-        .collect();
-
-    // 2. 遍历并删除这些键
-    for key in keys_to_remove {
-      question_map.remove(&key);
+      if !cache_map.contains_key(name) {
+        cache_map.insert(name.clone(), Pair { first: Conversation::new(tmp_kvcahce.len()), second: tmp_kvcahce.fork() });
+      }
+      queue.running = true;
+      let name = name.clone();
+      thread::spawn(move || work(name));
     }
     //println!("accept question");
   }
 }
 
 fn main() {
+  // 无界面模式: 只启动 OpenAI 兼容的 HTTP 服务
+  let args: Vec<String> = std::env::args().collect();
+  if args.iter().any(|arg| arg == "--server") {
+    let port = args
+      .iter()
+      .position(|arg| arg == "--port")
+      .and_then(|i| args.get(i + 1))
+      .map_or("8080", |port| port.as_str());
+    server::run(&format!("127.0.0.1:{port}"));
+    return;
+  }
 
   thread::spawn(move || accept());
 
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![greet, deal_question, send_answer, reset_question])
+        .invoke_handler(tauri::generate_handler![greet, deal_question, send_answer, send_alternatives, send_logprobs, question_status, list_question_status, embed_texts, prompt_logprobs, reset_question, regenerate_answer, list_answers, select_answer, list_branches, switch_branch, export_session, import_session, create_session, list_sessions, rename_session, delete_session, fork_session, search_history])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[test]
+fn test_failed_regenerate_rebuilds_cache() {
+  let name = "test_failed_regenerate".to_string();
+  lock(&CACHE_MAP).insert(name.clone(), Pair { first: Conversation::new(PREAMBLE_CACHE.len()), second: PREAMBLE_CACHE.fork() });
+  let question = |task, grammar: &str, max_len| {
+    let options = GenerateOptions { grammar: Some(grammar.into()), max_len, top_k: 1, ..GenerateOptions::default() };
+    Question { id: "1".into(), text: "hello".into(), options: Some(options), task }
+  };
+  infer(name.clone(), question(Task::Ask, r#"root ::= "yes""#, 10));
+  // 重新生成的答案写到原答案的位置后被 max_len 截断
+  infer(name.clone(), question(Task::Regenerate, r#"root ::= "no" "no" "no" "no""#, 2));
+  let status = question_status(name.clone(), "1".into()).unwrap();
+  assert_eq!(status.state, AnswerState::Failed);
+  let cache_map = lock(&CACHE_MAP);
+  let session = &cache_map[&name];
+  assert_eq!(session.first.answers("1"), vec!["yes".to_string()]);
+  // cache 与从开场白起 prefill 当前分支得到的完全相同
+  let tokens = session.first.active_tokens(usize::MAX);
+  let mut fresh = PREAMBLE_CACHE.fork();
+  LLAMACOM.forward(&Tensor::<u32>::new(tokens.clone(), &[tokens.len()]), &mut fresh);
+  assert_eq!(session.second.len(), fresh.len());
+  for layer in 0..fresh.layers() {
+    assert_eq!(session.second.keys(layer).data(), fresh.keys(layer).data());
+    assert_eq!(session.second.values(layer).data(), fresh.values(layer).data());
+  }
+}
+
+#[test]
+fn test_withdraw_queued_regenerate() {
+  let name = "test_withdraw_queued_regenerate";
+  regenerate_answer(name, "1".into(), None);
+  reset_question(name.into(), "1".into());
+  // 重新生成照常排队, 撤销排在它之后, 不把答案标记为取消
+  let tasks: Vec<Task> = lock(&QUESTION_MAP)[name].questions.iter().map(|q| q.task).collect();
+  assert!(tasks == [Task::Regenerate, Task::Withdraw]);
+  assert_eq!(question_status(name.into(), "1".into()).unwrap().state, AnswerState::Queued);
+  // 还在排队的提问直接取消
+  deal_question("hello", name, "2".into(), None);
+  reset_question(name.into(), "2".into());
+  assert_eq!(lock(&QUESTION_MAP)[name].questions.len(), 2);
+  assert_eq!(question_status(name.into(), "2".into()).unwrap().state, AnswerState::Cancelled);
+}
@@ -0,0 +1,190 @@
+// Full-text search over the questions and answers of every session, ranked with BM25.
+// Words are lowercased runs of letters and digits; Chinese/Japanese/Korean text has no spaces,
+// so each of its characters is a term, and so is each pair of adjacent characters, which
+// ranks exact phrases above scattered characters.
+use std::collections::HashMap;
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+const SNIPPET_CHARS: usize = 40; // context kept on each side of the first match
+
+// One searchable turn
+pub struct Document {
+    pub session: String,
+    pub id: String,
+    pub alternative: usize,
+    pub question: String,
+    pub answer: String,
+}
+
+// 搜索结果: 对话名, 问题 id 及第几个答案, 用于跳转
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct SearchHit {
+    pub session: String,
+    pub id: String,
+    pub alternative: usize,
+    pub score: f32,
+    pub snippet: String,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'     // hiragana, katakana
+        | '\u{3400}'..='\u{4DBF}'   // CJK extension A
+        | '\u{4E00}'..='\u{9FFF}'   // CJK unified ideographs
+        | '\u{AC00}'..='\u{D7AF}'   // hangul syllables
+        | '\u{F900}'..='\u{FAFF}')  // CJK compatibility ideographs
+}
+
+pub fn terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut word = String::new();
+    let mut prev_cjk: Option<char> = None;
+    for c in text.chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                terms.push(std::mem::take(&mut word));
+            }
+            terms.push(c.to_string());
+            if let Some(prev) = prev_cjk {
+                terms.push(format!("{prev}{c}"));
+            }
+            prev_cjk = Some(c);
+            continue;
+        }
+        prev_cjk = None;
+        if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        } else if !word.is_empty() {
+            terms.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        terms.push(word);
+    }
+    terms
+}
+
+// The best `limit` matches for `query`, best first
+pub fn search(documents: &[Document], query: &str, limit: usize) -> Vec<SearchHit> {
+    let mut query_terms = terms(query);
+    query_terms.sort();
+    query_terms.dedup();
+    if query_terms.is_empty() || documents.is_empty() {
+        return Vec::new();
+    }
+
+    let term_counts: Vec<HashMap<String, usize>> = documents
+        .iter()
+        .map(|doc| {
+            let mut counts = HashMap::new();
+            for term in terms(&doc.question).into_iter().chain(terms(&doc.answer)) {
+                *counts.entry(term).or_insert(0) += 1;
+            }
+            counts
+        })
+        .collect();
+    let lengths: Vec<f32> = term_counts.iter().map(|counts| counts.values().sum::<usize>() as f32).collect();
+    let avg_len = (lengths.iter().sum::<f32>() / documents.len() as f32).max(1.);
+    let n = documents.len() as f32;
+    let idf: Vec<f32> = query_terms
+        .iter()
+        .map(|term| {
+            let df = term_counts.iter().filter(|counts| counts.contains_key(term)).count() as f32;
+            (1. + (n - df + 0.5) / (df + 0.5)).ln()
+        })
+        .collect();
+
+    let mut scored: Vec<(usize, f32)> = term_counts
+        .iter()
+        .zip(&lengths)
+        .map(|(counts, &len)| {
+            query_terms
+                .iter()
+                .zip(&idf)
+                .map(|(term, idf)| {
+                    let tf = *counts.get(term).unwrap_or(&0) as f32;
+                    idf * tf * (K1 + 1.) / (tf + K1 * (1. - B + B * len / avg_len))
+                })
+                .sum()
+        })
+        .enumerate()
+        .filter(|&(_, score)| score > 0.)
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(i, score)| {
+            let doc = &documents[i];
+            SearchHit {
+                session: doc.session.clone(),
+                id: doc.id.clone(),
+                alternative: doc.alternative,
+                score,
+                snippet: snippet(doc, &query_terms),
+            }
+        })
+        .collect()
+}
+
+// The text around the first query term in the question, else in the answer
+fn snippet(doc: &Document, query_terms: &[String]) -> String {
+    for text in [&doc.question, &doc.answer] {
+        let chars: Vec<char> = text.chars().collect();
+        let lower: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+        let first = query_terms
+            .iter()
+            .filter_map(|term| {
+                let term: Vec<char> = term.chars().collect();
+                lower.windows(term.len()).position(|window| window == term.as_slice())
+            })
+            .min();
+        if let Some(pos) = first {
+            let start = pos.saturating_sub(SNIPPET_CHARS);
+            let end = (pos + SNIPPET_CHARS).min(chars.len());
+            let mut snippet: String = chars[start..end].iter().collect();
+            snippet = snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+            if start > 0 {
+                snippet.insert(0, '…');
+            }
+            if end < chars.len() {
+                snippet.push('…');
+            }
+            return snippet;
+        }
+    }
+    doc.question.chars().take(2 * SNIPPET_CHARS).collect()
+}
+
+#[test]
+fn test_terms() {
+    assert_eq!(terms("Hello, Rust-lang 2024!"), ["hello", "rust", "lang", "2024"]);
+    assert_eq!(terms("用Rust写"), ["用", "rust", "写"]);
+    assert_eq!(terms("天空蓝"), ["天", "空", "天空", "蓝", "空蓝"]);
+}
+
+#[test]
+fn test_search() {
+    let doc = |id: &str, question: &str, answer: &str| Document {
+        session: "对话1".into(),
+        id: id.into(),
+        alternative: 0,
+        question: question.into(),
+        answer: answer.into(),
+    };
+    let documents = [
+        doc("1", "天空为什么是蓝色的", "因为瑞利散射, 短波长的蓝光散射得更多"),
+        doc("2", "How do I read a file in Rust?", "Use std::fs::read_to_string to read the whole file."),
+        doc("3", "推荐一本书", "《三体》, 讲的是天文和物理"),
+    ];
+    let hits = search(&documents, "蓝色天空", 10);
+    assert_eq!(hits[0].id, "1");
+    assert!(hits.iter().all(|hit| hit.id != "2"));
+    let hits = search(&documents, "rust FILE", 10);
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].id, "2");
+    assert!(hits[0].snippet.contains("Rust"));
+    assert!(search(&documents, "量子", 10).is_empty());
+    assert!(search(&documents, "  ", 10).is_empty());
+}
@@ -0,0 +1,280 @@
+// Headless OpenAI-compatible HTTP API, started with `chat-tauri --server [--port 8080]`.
+// Serves /v1/models, /v1/chat/completions and /v1/completions (with `"stream": true` as
+// server-sent events) on localhost, using the same model, tokenizer, ChatML template and
+// generate::generate dispatch as the chat window. A fixed number of worker threads take
+// requests in turn, so at most WORKERS caches are alive at once.
+// Requests do not go through the window's session queue (QUESTION_MAP / work): the server runs
+// instead of the window, never next to it, and every request carries its whole conversation,
+// so there is no session whose cache would be worth keeping. work() also holds CACHE_MAP for a
+// whole answer, which would run the workers one at a time.
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use chat_core::generate::{self, GenerateOptions};
+use chat_core::model::TokenLogprob;
+use chat_core::template::{self, Message};
+
+use crate::{token_text, LLAMACOM, TOKENIZER, TOKEN_DECODER, VOCAB_TRIE};
+
+pub const MODEL_ID: &str = "chat";
+
+// Requests answered at the same time; the others wait in the listener's queue
+const WORKERS: usize = 2;
+
+// Fields shared by both completion endpoints. Besides the OpenAI ones every GenerateOptions
+// field the chat window takes (beam_width, grammar, json_schema, logprobs, ..) is read under
+// the same name; unset ones fall back to the defaults.
+#[derive(serde::Deserialize)]
+struct Params {
+    max_tokens: Option<usize>,
+    n: Option<usize>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    options: GenerateOptions,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatRequest {
+    messages: Vec<Message>,
+    #[serde(flatten)]
+    params: Params,
+}
+
+#[derive(serde::Deserialize)]
+struct CompletionRequest {
+    prompt: String,
+    #[serde(flatten)]
+    params: Params,
+}
+
+pub fn run(addr: &str) {
+    let server = Arc::new(Server::http(addr).unwrap());
+    println!("OpenAI-compatible server listening on http://{addr}/v1");
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+fn handle(mut request: Request) {
+    let mut body = String::new();
+    if request.as_reader().read_to_string(&mut body).is_err() {
+        return respond_error(request, 400, "request body is not valid UTF-8");
+    }
+    let path = request.url().split('?').next().unwrap_or_default().to_string();
+    println!("{} {}", request.method(), path);
+    match (request.method(), path.as_str()) {
+        (Method::Get, "/v1/models") => {
+            let models = json!({
+                "object": "list",
+                "data": [{ "id": MODEL_ID, "object": "model", "created": 0, "owned_by": "local" }],
+            });
+            respond_json(request, 200, &models)
+        }
+        (Method::Post, "/v1/chat/completions") => match serde_json::from_str::<ChatRequest>(&body) {
+            Ok(req) => complete(request, &template::chat_prompt(&req.messages), req.params, true),
+            Err(e) => respond_error(request, 400, &e.to_string()),
+        },
+        (Method::Post, "/v1/completions") => match serde_json::from_str::<CompletionRequest>(&body) {
+            Ok(req) => complete(request, &req.prompt, req.params, false),
+            Err(e) => respond_error(request, 400, &e.to_string()),
+        },
+        _ => respond_error(request, 404, &format!("no route for {path}")),
+    }
+}
+
+fn complete(request: Request, prompt: &str, params: Params, chat: bool) {
+    let Params { max_tokens, n, stream, mut options } = params;
+    let n = n.unwrap_or(1).max(1);
+    if max_tokens == Some(0) {
+        return respond_error(request, 400, "max_tokens must be at least 1");
+    }
+    if let Err(e) = generate::grammar(&options) {
+        return respond_error(request, 400, &e);
+    }
+
+    let binding = TOKENIZER.encode(prompt, true).unwrap();
+    let input_ids = binding.get_ids();
+    // an explicit max_tokens must fit next to the prompt; the default is cut to what is left
+    let max_seq_len = LLAMACOM.max_seq_len();
+    let room = max_seq_len.saturating_sub(input_ids.len());
+    let max_len = match max_tokens {
+        Some(max_tokens) => max_tokens,
+        None => options.max_len.min(room).max(1),
+    };
+    if input_ids.len() + max_len > max_seq_len {
+        let message = format!(
+            "this model's maximum context length is {max_seq_len} tokens, but {} were requested ({} in the prompt, {max_len} for the completion)",
+            input_ids.len() + max_len,
+            input_ids.len(),
+        );
+        return respond_error(request, 400, &message);
+    }
+    options.max_len = max_len;
+    options.n_samples = n;
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let id = format!("{}-{}", if chat { "chatcmpl" } else { "cmpl" }, rand::random::<u32>());
+    let mut cache = LLAMACOM.new_cache();
+
+    if stream {
+        if n > 1 {
+            return respond_error(request, 400, "n > 1 is not supported with stream");
+        }
+        let mut writer = request.into_writer();
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nTransfer-Encoding: chunked\r\n\r\n";
+        if writer.write_all(head.as_bytes()).is_err() {
+            return;
+        }
+        let chunk = |delta: &str, role: bool, finish: Value| {
+            let choice = match (chat, role) {
+                (true, true) => json!({ "index": 0, "delta": { "role": "assistant", "content": "" }, "finish_reason": finish }),
+                (true, false) => json!({ "index": 0, "delta": { "content": delta }, "finish_reason": finish }),
+                (false, _) => json!({ "index": 0, "text": delta, "finish_reason": finish }),
+            };
+            let object = if chat { "chat.completion.chunk" } else { "text_completion" };
+            json!({ "id": id, "object": object, "created": created, "model": MODEL_ID, "choices": [choice] })
+        };
+        if chat && !send_event(&mut writer, &chunk("", true, Value::Null)) {
+            return;
+        }
+        // the detokenizer holds back a multi-byte char until all of its tokens have arrived
+        let mut detokenizer = TOKEN_DECODER.detokenizer();
+        let (mut streamed, mut connected) = (0, true);
+        let generation = generate::generate(&LLAMACOM, input_ids, &mut cache, None, &options, || &VOCAB_TRIE, |tok| {
+            streamed += 1;
+            let piece = detokenizer.push(tok);
+            connected = piece.is_empty() || send_event(&mut writer, &chunk(&piece, false, Value::Null));
+            connected
+        });
+        if !connected {
+            return;
+        }
+        let output = match generation {
+            Ok(generation) => generation.candidates.into_iter().next().map_or(Vec::new(), |(tokens, _)| tokens),
+            Err(e) => {
+                let error = json!({ "error": { "message": e, "type": "invalid_request_error" } });
+                if send_event(&mut writer, &error) && send_chunk(&mut writer, "data: [DONE]\n\n") {
+                    send_chunk(&mut writer, "");
+                }
+                return;
+            }
+        };
+        // only plain sampling streams its tokens, the other modes return the answer at the end
+        let mut rest: String = output[streamed..].iter().map(|&tok| detokenizer.push(tok)).collect();
+        rest.push_str(&detokenizer.finish());
+        if !rest.is_empty() && !send_event(&mut writer, &chunk(&rest, false, Value::Null)) {
+            return;
+        }
+        let finish = finish_reason(&output);
+        if send_event(&mut writer, &chunk("", false, json!(finish))) && send_chunk(&mut writer, "data: [DONE]\n\n") {
+            send_chunk(&mut writer, "");
+        }
+        return;
+    }
+
+    let generation = match generate::generate(&LLAMACOM, input_ids, &mut cache, None, &options, || &VOCAB_TRIE, |_| true) {
+        Ok(generation) => generation,
+        Err(e) => return respond_error(request, 400, &e),
+    };
+    let answers = &generation.candidates[..n.min(generation.candidates.len())];
+    let choices: Vec<Value> = answers
+        .iter()
+        .enumerate()
+        .map(|(i, (tokens, _))| {
+            let text = TOKEN_DECODER.decode(tokens);
+            let finish = finish_reason(tokens);
+            let mut choice = match chat {
+                true => json!({ "index": i, "message": { "role": "assistant", "content": text }, "finish_reason": finish }),
+                false => json!({ "index": i, "text": text, "finish_reason": finish }),
+            };
+            if let (0, Some(steps)) = (i, &generation.logprobs) {
+                choice["logprobs"] = logprobs(steps, chat);
+            }
+            choice
+        })
+        .collect();
+    let completion_tokens: usize = answers.iter().map(|(tokens, _)| tokens.len()).sum();
+    let response = json!({
+        "id": id,
+        "object": if chat { "chat.completion" } else { "text_completion" },
+        "created": created,
+        "model": MODEL_ID,
+        "choices": choices,
+        "usage": {
+            "prompt_tokens": input_ids.len(),
+            "completion_tokens": completion_tokens,
+            "total_tokens": input_ids.len() + completion_tokens,
+        },
+    });
+    respond_json(request, 200, &response)
+}
+
+// Per-token logprobs in the format of each endpoint: a list of tokens with their top
+// alternatives for chat, parallel lists for the legacy completions
+fn logprobs(steps: &[TokenLogprob], chat: bool) -> Value {
+    fn top(step: &TokenLogprob) -> impl Iterator<Item = (String, f32)> + '_ {
+        step.top.iter().map(|&(id, logprob)| (token_text(id), logprob))
+    }
+    if chat {
+        let content: Vec<Value> = steps
+            .iter()
+            .map(|step| {
+                let top: Vec<Value> = top(step).map(|(token, logprob)| json!({ "token": token, "logprob": logprob })).collect();
+                json!({ "token": token_text(step.token), "logprob": step.logprob, "top_logprobs": top })
+            })
+            .collect();
+        json!({ "content": content })
+    } else {
+        json!({
+            "tokens": steps.iter().map(|step| token_text(step.token)).collect::<Vec<_>>(),
+            "token_logprobs": steps.iter().map(|step| step.logprob).collect::<Vec<_>>(),
+            "top_logprobs": steps.iter().map(|step| top(step).map(|(token, logprob)| (token, json!(logprob))).collect::<serde_json::Map<_, _>>()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn finish_reason(tokens: &[u32]) -> &'static str {
+    match tokens.last() == Some(&LLAMACOM.eos_token_id()) {
+        true => "stop",
+        false => "length",
+    }
+}
+
+// Writes one SSE event; false once the client has gone away
+fn send_event(writer: &mut Box<dyn Write + Send>, value: &Value) -> bool {
+    send_chunk(writer, &format!("data: {value}\n\n"))
+}
+
+// One chunk of the chunked response body, the empty one ends the response
+fn send_chunk(writer: &mut Box<dyn Write + Send>, data: &str) -> bool {
+    writer
+        .write_all(format!("{:x}\r\n{data}\r\n", data.len()).as_bytes())
+        .and_then(|_| writer.flush())
+        .is_ok()
+}
+
+fn respond_json(request: Request, status: u16, value: &Value) {
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = Response::from_string(value.to_string()).with_status_code(status).with_header(header);
+    let _ = request.respond(response);
+}
+
+fn respond_error(request: Request, status: u16, message: &str) {
+    let error = json!({ "error": { "message": message, "type": "invalid_request_error" } });
+    respond_json(request, status, &error)
+}
@@ -67,6 +67,7 @@ export const useChatStore = defineStore('chat', () => {
                 status: false
             }
             nowChatName.value = chatName
+            invoke('create_session', { name: chatName }).then((res: any) => {})
             ElMessage({
                 message: '新建成功',
                 type: 'success'
@@ -104,9 +105,16 @@ export const useChatStore = defineStore('chat', () => {
             const nowItem = ChatObj.value[nowChatName.value][index]
             const name = nowChatName.value
             nowItem.timer = setInterval(() => {
-                invoke('send_answer', { name: name }).then((res: any) => {
-                    if (res) {
+                // 返回 null 表示还没处理完; 失败时为错误信息, 取消时为空串
+                invoke('send_answer', { name: name, id: id }).then((res: any) => {
+                    if (res !== null) {
                         console.log(res, '---', name, id, ChatObj.value)
+                        // 问题可能已被撤销, 不在列表中了
+                        clearInterval(nowItem.timer)
+                        nowItem.timer = null
+                        if (statusObj.value[name]) {
+                            statusObj.value[name].status = false
+                        }
                         for (let chatName in ChatObj.value) {
                             const index = ChatObj.value[chatName].findIndex((item) => item.id == id)
                             if (index !== -1) {
@@ -145,6 +153,21 @@ export const useChatStore = defineStore('chat', () => {
         console.log('删除后的数据', ChatObj.value[nowChatName.value])
     }
 
+    /**
+     * 删除对话, 同时释放后端的 KVCache
+     * @param chatName
+     */
+    const deleteChat = (chatName: string) => {
+        ChatObj.value[chatName]?.forEach((item) => clearInterval(item.timer))
+        delete ChatObj.value[chatName]
+        delete statusObj.value[chatName]
+        if (nowChatName.value === chatName) {
+            nowChatName.value = chatRoomList.value[0] ?? ''
+        }
+        invoke('delete_session', { name: chatName }).then((res: any) => {})
+        console.log('删除对话后的数据', ChatObj.value)
+    }
+
     /**
      * @param chatName 切换对话
      */
@@ -152,5 +175,5 @@ export const useChatStore = defineStore('chat', () => {
         nowChatName.value = chatName
     }
 
-    return { chatList, addChat, nowChatName, sendQuestion, resetQuestion, deleteQuestion, chatRoomList, changeChatRoom, statusObj }
+    return { chatList, addChat, deleteChat, nowChatName, sendQuestion, resetQuestion, deleteQuestion, chatRoomList, changeChatRoom, statusObj }
 })